@@ -7,17 +7,59 @@ impl ValueEnum for config::WindowMatcher {
         match self {
             config::WindowMatcher::Process => Some("process".into()),
             config::WindowMatcher::Class => Some("class".into()),
+            config::WindowMatcher::Instance => Some("instance".into()),
+            config::WindowMatcher::Role => Some("role".into()),
+            config::WindowMatcher::Sandbox => Some("sandbox".into()),
+            config::WindowMatcher::Title => Some("title".into()),
         }
     }
 
     fn value_variants<'a>() -> &'a [Self] {
-        &[config::WindowMatcher::Class, config::WindowMatcher::Process]
+        &[
+            config::WindowMatcher::Class,
+            config::WindowMatcher::Instance,
+            config::WindowMatcher::Process,
+            config::WindowMatcher::Role,
+            config::WindowMatcher::Sandbox,
+            config::WindowMatcher::Title,
+        ]
     }
 
     fn from_str(input: &str, _ignore_case: bool) -> Result<Self, String> {
         match input.to_lowercase().as_str() {
             "process" => Ok(Self::Process),
             "class" => Ok(Self::Class),
+            "instance" => Ok(Self::Instance),
+            "role" => Ok(Self::Role),
+            "sandbox" => Ok(Self::Sandbox),
+            "title" => Ok(Self::Title),
+            _ => Err("Invalid value".into()),
+        }
+    }
+}
+
+impl ValueEnum for config::TitleMatch {
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        match self {
+            config::TitleMatch::Exact => Some("exact".into()),
+            config::TitleMatch::Substring => Some("substring".into()),
+            config::TitleMatch::Regex => Some("regex".into()),
+        }
+    }
+
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            config::TitleMatch::Exact,
+            config::TitleMatch::Substring,
+            config::TitleMatch::Regex,
+        ]
+    }
+
+    fn from_str(input: &str, _ignore_case: bool) -> Result<Self, String> {
+        match input.to_lowercase().as_str() {
+            "exact" => Ok(Self::Exact),
+            "substring" => Ok(Self::Substring),
+            "regex" => Ok(Self::Regex),
             _ => Err("Invalid value".into()),
         }
     }
@@ -39,6 +81,22 @@ pub enum Command {
         matcher: config::WindowMatcher,
         #[arg(long, value_enum)]
         class_name: Option<String>,
+        /// The instance half of the window's WM_CLASS, when using the instance matcher.
+        #[arg(long)]
+        instance_name: Option<String>,
+        /// The window's WM_WINDOW_ROLE, when using the role matcher.
+        #[arg(long)]
+        role: Option<String>,
+        /// The window title to look for, when using the title matcher.
+        #[arg(long)]
+        title: Option<String>,
+        /// How the title is compared against window titles.
+        #[arg(long, value_enum, default_value_t = config::TitleMatch::Exact)]
+        title_match: config::TitleMatch,
+        /// The app ID of a sandboxed app, when using the sandbox matcher. Inferred from
+        /// `flatpak run` commands if not given.
+        #[arg(long)]
+        app_id: Option<String>,
         /// the name of the application instance
         #[arg(required = true)]
         name: String,
@@ -61,6 +119,49 @@ pub enum Command {
         /// the name of the application instance
         name: String,
     },
+    /// Manage the active window as a new application instance, hiding it until toggled.
+    Capture {
+        /// the name to give the application instance
+        name: String,
+    },
+    /// Stop managing a captured application instance, leaving its window visible.
+    Release {
+        /// the name of the captured application instance
+        name: String,
+    },
+    /// Report the state of a running application instance.
+    Status {
+        /// the name of the application instance
+        name: String,
+        /// Print the status as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Stop managing an application instance, or shut down the daemon if no instance is given.
+    ///
+    /// Applications are left running and visible unless `--kill` is passed.
+    Shutdown {
+        /// the name of the application instance
+        name: Option<String>,
+        /// Kill the application(s) rather than leaving them running.
+        #[arg(long)]
+        kill: bool,
+    },
+    /// Show windows left hidden by a qurop daemon that is no longer running.
+    Recover,
+    /// List the application instances managed by the daemon.
+    List {
+        /// Print the list as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run the daemon in the foreground, managing every configured application instance.
+    Daemon,
+    /// Stream events (shown, hidden, exited, ...) from a running application instance as JSON lines.
+    Watch {
+        /// the name of the application instance
+        name: String,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -1,35 +1,553 @@
-use std::{collections::BTreeMap, fs::create_dir_all, path::PathBuf};
+use std::{collections::BTreeMap, fmt, fs::create_dir_all, path::PathBuf, time::Duration};
 
 use directories::ProjectDirs;
-use serde::{Deserialize, Serialize};
+use regex::Regex;
+use serde::{
+    de::{self, IntoDeserializer, MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use tracing::debug;
 
-use crate::{errors::Error, structs::WindowGeometry, utils::abort};
+use crate::{
+    errors::Error,
+    structs,
+    structs::{AdoptPolicy, Geometry, ReconnectPolicy, RestartPolicy, Signal, WindowGeometry},
+    utils::abort,
+};
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) enum WindowMatcher {
+    #[default]
     Process,
     Class,
+    Instance,
+    Role,
+    Title,
+    Sandbox,
+}
+
+/// How the `title` of a title matcher is compared against window titles.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum TitleMatch {
+    #[default]
+    Exact,
+    Substring,
+    Regex,
+}
+
+/// A matcher as written in the config. This is either the name of a matcher configured by its
+/// sibling keys (e.g., `matcher = "class"` alongside `class_name`), as written by `qurop add`, or a
+/// rule that may combine several criteria (e.g.,
+/// `matcher = { all = [{ class = "firefox" }, { title = { regex = "^Notes" } }] }`).
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub(crate) enum Matcher {
+    Kind(WindowMatcher),
+    Rule(MatchRule),
 }
 
-impl Default for WindowMatcher {
+impl Default for Matcher {
     fn default() -> Self {
-        Self::Process
+        Matcher::Kind(WindowMatcher::default())
     }
 }
 
+/// A single criterion, or a combination of them. Every matcher is resolved through a rule.
+#[derive(Debug, Clone)]
+pub(crate) enum MatchRule {
+    /// Windows of the launched program, or of any process it started.
+    Process,
+    /// Windows of a sandboxed app started by the launched program.
+    SandboxedProcess,
+    Class(String),
+    Instance(String),
+    Role(String),
+    Title(TitleRule),
+    /// A sandboxed app, by app ID.
+    Sandbox(String),
+    /// Every rule must match.
+    All(Vec<MatchRule>),
+    /// At least one rule must match.
+    Any(Vec<MatchRule>),
+    /// The rule must not match.
+    Not(Box<MatchRule>),
+}
+
+/// A title criterion within a matcher rule.
+#[derive(Debug, Clone)]
+pub(crate) enum TitleRule {
+    Exact(String),
+    Substring(String),
+    Regex(String),
+}
+
+/// The names of the rules, as written in the config.
+const RULES: &[&str] = &[
+    "process",
+    "sandboxed-process",
+    "class",
+    "instance",
+    "role",
+    "title",
+    "sandbox",
+    "all",
+    "any",
+    "not",
+];
+
+/// Reads a rule. Rules without a value can be written as a bare name (e.g., `"process"`) or as a
+/// table (e.g., `{ process = true }`), and the rest as a single-entry table (e.g.,
+/// `{ class = "firefox" }`).
+struct RuleVisitor;
+
+impl RuleVisitor {
+    /// Get the rule without a value that has the given name.
+    fn named<E: de::Error>(name: &str) -> Result<MatchRule, E> {
+        match name {
+            "process" => Ok(MatchRule::Process),
+            "sandboxed-process" => Ok(MatchRule::SandboxedProcess),
+            _ if RULES.contains(&name) => Err(E::custom(format!(
+                "the '{name}' rule needs a value, e.g. {{ {name} = ... }}"
+            ))),
+            _ => Err(E::unknown_variant(name, RULES)),
+        }
+    }
+}
+
+impl<'de> Visitor<'de> for RuleVisitor {
+    type Value = MatchRule;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a matcher rule, such as \"process\" or { class = \"firefox\" }")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<MatchRule, E> {
+        Self::named(name)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<MatchRule, A::Error> {
+        let Some(name) = map.next_key::<String>()? else {
+            return Err(de::Error::invalid_length(0, &self));
+        };
+        let rule = match name.as_str() {
+            "process" | "sandboxed-process" => {
+                if !map.next_value::<bool>()? {
+                    return Err(de::Error::custom(format!(
+                        "the '{name}' rule can't be false, use {{ not = \"{name}\" }} instead"
+                    )));
+                }
+                Self::named(&name)?
+            }
+            "class" => MatchRule::Class(map.next_value()?),
+            "instance" => MatchRule::Instance(map.next_value()?),
+            "role" => MatchRule::Role(map.next_value()?),
+            "title" => MatchRule::Title(map.next_value()?),
+            "sandbox" => MatchRule::Sandbox(map.next_value()?),
+            "all" => MatchRule::All(map.next_value()?),
+            "any" => MatchRule::Any(map.next_value()?),
+            "not" => MatchRule::Not(map.next_value()?),
+            _ => return Err(de::Error::unknown_variant(&name, RULES)),
+        };
+        if map.next_key::<String>()?.is_some() {
+            return Err(de::Error::custom(
+                "a rule has a single key, combine rules with `all` or `any`",
+            ));
+        }
+        Ok(rule)
+    }
+}
+
+impl<'de> Deserialize<'de> for MatchRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(RuleVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for Matcher {
+    /// Read the name of a matcher configured by its sibling keys, or otherwise a rule. Only the
+    /// top-level matcher can be named, so `"class"` within a rule is an error rather than quietly
+    /// reading `class_name`.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MatcherVisitor;
+
+        impl<'de> Visitor<'de> for MatcherVisitor {
+            type Value = Matcher;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("the name of a matcher, or a matcher rule")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<Matcher, E> {
+                let kind: Result<_, E> = WindowMatcher::deserialize(name.into_deserializer());
+                match kind {
+                    Ok(kind) => Ok(Matcher::Kind(kind)),
+                    Err(_) => RuleVisitor.visit_str(name).map(Matcher::Rule),
+                }
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Matcher, A::Error> {
+                RuleVisitor.visit_map(map).map(Matcher::Rule)
+            }
+        }
+
+        deserializer.deserialize_any(MatcherVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for TitleRule {
+    /// Read a single-entry table (e.g., `{ regex = "^Notes" }`). Written by hand, as the TOML
+    /// deserializer only reads enums from inline tables.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TitleVisitor;
+
+        impl<'de> Visitor<'de> for TitleVisitor {
+            type Value = TitleRule;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a title rule, such as { regex = \"^Notes\" }")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<TitleRule, A::Error> {
+                const KINDS: &[&str] = &["exact", "substring", "regex"];
+                let Some(kind) = map.next_key::<String>()? else {
+                    return Err(de::Error::invalid_length(0, &self));
+                };
+                let rule = match kind.as_str() {
+                    "exact" => TitleRule::Exact(map.next_value()?),
+                    "substring" => TitleRule::Substring(map.next_value()?),
+                    "regex" => TitleRule::Regex(map.next_value()?),
+                    _ => return Err(de::Error::unknown_variant(&kind, KINDS)),
+                };
+                if map.next_key::<String>()?.is_some() {
+                    return Err(de::Error::invalid_length(2, &self));
+                }
+                Ok(rule)
+            }
+        }
+
+        deserializer.deserialize_any(TitleVisitor)
+    }
+}
+
+/// Serialize a rule as a single-entry table. Rules without a value are tables too, as the TOML
+/// serializer can't write arrays mixing strings and tables.
+fn serialize_entry<S: Serializer, T: Serialize + ?Sized>(
+    serializer: S,
+    key: &str,
+    value: &T,
+) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(1))?;
+    map.serialize_entry(key, value)?;
+    map.end()
+}
+
+impl Serialize for MatchRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            MatchRule::Process => serialize_entry(serializer, "process", &true),
+            MatchRule::SandboxedProcess => serialize_entry(serializer, "sandboxed-process", &true),
+            MatchRule::Class(class_name) => serialize_entry(serializer, "class", class_name),
+            MatchRule::Instance(instance_name) => {
+                serialize_entry(serializer, "instance", instance_name)
+            }
+            MatchRule::Role(role) => serialize_entry(serializer, "role", role),
+            MatchRule::Title(title) => serialize_entry(serializer, "title", title),
+            MatchRule::Sandbox(app_id) => serialize_entry(serializer, "sandbox", app_id),
+            MatchRule::All(rules) => serialize_entry(serializer, "all", rules),
+            MatchRule::Any(rules) => serialize_entry(serializer, "any", rules),
+            MatchRule::Not(rule) => serialize_entry(serializer, "not", rule),
+        }
+    }
+}
+
+impl Serialize for TitleRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            TitleRule::Exact(title) => serialize_entry(serializer, "exact", title),
+            TitleRule::Substring(title) => serialize_entry(serializer, "substring", title),
+            TitleRule::Regex(title) => serialize_entry(serializer, "regex", title),
+        }
+    }
+}
+
+/// The command an instance runs. This is either a command line run by the shell, or a program
+/// and its arguments, run directly (e.g., `command = ["wezterm", "start", "--class", "dropdown"]`).
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub(crate) enum Command {
+    Shell(String),
+    Args(Vec<String>),
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Command::Shell(String::new())
+    }
+}
+
+impl Command {
+    /// Get the words making up the command.
+    fn words(&self) -> Vec<&str> {
+        match self {
+            Command::Shell(line) => line.split_whitespace().collect(),
+            Command::Args(args) => args.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub(crate) struct Instance {
-    pub(crate) command: String,
+    pub(crate) command: Command,
+    /// Variables removed from the program's environment.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) unset_env: Vec<String>,
+    /// The directory the program is started in.
     #[serde(default)]
-    pub(crate) matcher: WindowMatcher,
+    pub(crate) cwd: Option<PathBuf>,
     #[serde(default)]
     pub(crate) class_name: Option<String>,
+    /// The instance half of `WM_CLASS`, which many terminals let you set via `--name`.
+    #[serde(default)]
+    pub(crate) instance_name: Option<String>,
+    /// The window's `WM_WINDOW_ROLE`.
+    #[serde(default)]
+    pub(crate) role: Option<String>,
+    #[serde(default)]
+    pub(crate) title: Option<String>,
+    #[serde(default)]
+    pub(crate) title_match: TitleMatch,
+    /// The app ID of a sandboxed app, e.g. a flatpak's `md.obsidian.Obsidian`.
+    #[serde(default)]
+    pub(crate) app_id: Option<String>,
     #[serde(default)]
     pub(crate) window_delay_ms: Option<u64>,
     #[serde(default)]
+    pub(crate) on_reconnect: ReconnectPolicy,
+    #[serde(default)]
+    pub(crate) adopt: AdoptPolicy,
+    #[serde(default)]
+    pub(crate) restart: RestartPolicy,
+    /// The signal sent to stop the program, e.g. `SIGTERM` (the default) or `SIGHUP`.
+    #[serde(default)]
+    pub(crate) kill_signal: Option<String>,
+    /// How long to wait for the program to stop before killing it outright.
+    #[serde(default)]
+    pub(crate) kill_timeout_ms: Option<u64>,
+    // Settings that can be tables follow the rest, as TOML can't have values after a table.
+    #[serde(default)]
+    pub(crate) matcher: Matcher,
+    #[serde(default)]
     pub(crate) geometry: Option<WindowGeometry>,
+    /// Variables set in the program's environment.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) env: BTreeMap<String, String>,
+}
+
+/// Options of `flatpak run` that take the following argument as their value.
+const FLATPAK_RUN_VALUE_OPTIONS: &[&str] = &[
+    "--add-policy",
+    "--allow",
+    "--app-path",
+    "--arch",
+    "--branch",
+    "--command",
+    "--commit",
+    "--cwd",
+    "--device",
+    "--disallow",
+    "--env",
+    "--env-fd",
+    "--filesystem",
+    "--instance-id-fd",
+    "--nodevice",
+    "--nofilesystem",
+    "--nosocket",
+    "--no-talk-name",
+    "--own-name",
+    "--parent-pid",
+    "--persist",
+    "--remove-policy",
+    "--runtime",
+    "--runtime-commit",
+    "--runtime-version",
+    "--share",
+    "--socket",
+    "--system-no-talk-name",
+    "--system-own-name",
+    "--system-talk-name",
+    "--talk-name",
+    "--unset-env",
+    "--unshare",
+    "--usr-path",
+];
+
+/// Get the app ID from a `flatpak run` command, or `None` for any other command. Fails if the
+/// command runs something that doesn't look like an app ID.
+fn flatpak_app_id(command: &Command) -> Result<Option<String>, String> {
+    let mut args = command.words().into_iter();
+    if args.find(|arg| arg.ends_with("flatpak")).is_none()
+        || args.next().filter(|arg| *arg == "run").is_none()
+    {
+        return Ok(None);
+    }
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            // Refs (e.g., `app/org.foo.App/x86_64/stable`) start with the app ID.
+            let app_id = arg.strip_prefix("app/").unwrap_or(arg);
+            let app_id = app_id.split('/').next().unwrap_or_default();
+            if !app_id.contains('.') {
+                return Err(format!("'{arg}' doesn't look like a flatpak app ID"));
+            }
+            return Ok(Some(app_id.into()));
+        }
+        if FLATPAK_RUN_VALUE_OPTIONS.contains(&arg) {
+            args.next();
+        }
+    }
+    Err("the `flatpak run` command doesn't name an app".into())
+}
+
+/// Get a setting required by the instance's matcher.
+fn required(value: &Option<String>, name: &str, key: &str) -> Result<String, Error> {
+    value.clone().ok_or_else(|| Error::InvalidInstance {
+        name: name.into(),
+        reason: format!("'{key}' must be specified"),
+    })
+}
+
+/// Compile a title criterion.
+fn title_matcher(title: &TitleRule, name: &str) -> Result<structs::TitleMatcher, Error> {
+    Ok(match title {
+        TitleRule::Exact(title) => structs::TitleMatcher::Exact(title.clone()),
+        TitleRule::Substring(title) => structs::TitleMatcher::Substring(title.clone()),
+        TitleRule::Regex(title) => {
+            structs::TitleMatcher::Regex(Regex::new(title).map_err(|err| {
+                Error::InvalidInstance {
+                    name: name.into(),
+                    reason: format!("'{title}' is not a valid regex: {err}"),
+                }
+            })?)
+        }
+    })
+}
+
+/// Resolve a rule, and any rules nested within it.
+fn resolve_rule(rule: &MatchRule, name: &str) -> Result<structs::WindowMatcher, Error> {
+    let resolve_all = |rules: &[MatchRule]| {
+        rules
+            .iter()
+            .map(|rule| resolve_rule(rule, name))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    Ok(match rule {
+        MatchRule::Process => structs::WindowMatcher::ProcessId(None),
+        MatchRule::SandboxedProcess => structs::WindowMatcher::Sandbox {
+            pid: None,
+            app_id: None,
+        },
+        MatchRule::Class(class_name) => structs::WindowMatcher::WmClass(class_name.clone()),
+        MatchRule::Instance(instance_name) => {
+            structs::WindowMatcher::WmInstance(instance_name.clone())
+        }
+        MatchRule::Role(role) => structs::WindowMatcher::Role(role.clone()),
+        MatchRule::Title(title) => structs::WindowMatcher::Title(title_matcher(title, name)?),
+        MatchRule::Sandbox(app_id) => structs::WindowMatcher::Sandbox {
+            pid: None,
+            app_id: Some(app_id.clone()),
+        },
+        MatchRule::All(rules) => structs::WindowMatcher::All(resolve_all(rules)?),
+        MatchRule::Any(rules) => structs::WindowMatcher::Any(resolve_all(rules)?),
+        MatchRule::Not(rule) => structs::WindowMatcher::Not(Box::new(resolve_rule(rule, name)?)),
+    })
+}
+
+impl Instance {
+    /// Get the instance's matcher as a rule, building a matcher named by kind from its sibling keys.
+    fn rule(&self, name: &str) -> Result<MatchRule, Error> {
+        let kind = match &self.matcher {
+            Matcher::Kind(kind) => kind,
+            Matcher::Rule(rule) => return Ok(rule.clone()),
+        };
+        Ok(match kind {
+            WindowMatcher::Process => MatchRule::Process,
+            WindowMatcher::Class => {
+                MatchRule::Class(required(&self.class_name, name, "class_name")?)
+            }
+            WindowMatcher::Instance => {
+                MatchRule::Instance(required(&self.instance_name, name, "instance_name")?)
+            }
+            WindowMatcher::Role => MatchRule::Role(required(&self.role, name, "role")?),
+            WindowMatcher::Title => {
+                let title = required(&self.title, name, "title")?;
+                MatchRule::Title(match self.title_match {
+                    TitleMatch::Exact => TitleRule::Exact(title),
+                    TitleMatch::Substring => TitleRule::Substring(title),
+                    TitleMatch::Regex => TitleRule::Regex(title),
+                })
+            }
+            WindowMatcher::Sandbox => match &self.app_id {
+                Some(app_id) => MatchRule::Sandbox(app_id.clone()),
+                None => match flatpak_app_id(&self.command) {
+                    Ok(Some(app_id)) => MatchRule::Sandbox(app_id),
+                    Ok(None) => MatchRule::SandboxedProcess,
+                    Err(reason) => {
+                        return Err(Error::InvalidInstance {
+                            name: name.into(),
+                            reason: format!("{reason}, so 'app_id' must be specified"),
+                        })
+                    }
+                },
+            },
+        })
+    }
+
+    /// Resolve the configured instance into the form the daemon manages.
+    pub(crate) fn resolve(&self, name: &str) -> Result<structs::Instance, Error> {
+        Ok(structs::Instance {
+            name: name.into(),
+            command: match &self.command {
+                Command::Shell(line) => structs::Command::Shell(line.clone()),
+                Command::Args(args) => match args.split_first() {
+                    Some((program, args)) => structs::Command::Exec {
+                        program: program.clone(),
+                        args: args.to_vec(),
+                    },
+                    None => {
+                        return Err(Error::InvalidInstance {
+                            name: name.into(),
+                            reason: "'command' must not be empty".into(),
+                        })
+                    }
+                },
+            },
+            env: self.env.clone(),
+            unset_env: self.unset_env.clone(),
+            cwd: self.cwd.clone(),
+            matcher: resolve_rule(&self.rule(name)?, name)?,
+            window_delay: self.window_delay_ms.or(Some(100)),
+            geometry: match &self.geometry {
+                Some(geometry) => geometry.parse().map_err(|reason| Error::InvalidInstance {
+                    name: name.into(),
+                    reason,
+                })?,
+                None => Geometry::default(),
+            },
+            on_reconnect: self.on_reconnect,
+            adopt: self.adopt,
+            restart: self.restart,
+            kill_signal: match &self.kill_signal {
+                Some(signal) => signal.parse().map_err(|err| Error::InvalidInstance {
+                    name: name.into(),
+                    reason: format!("'{signal}' is not a valid kill_signal: {err}"),
+                })?,
+                None => Signal::default(),
+            },
+            kill_timeout: Duration::from_millis(self.kill_timeout_ms.unwrap_or(5000)),
+        })
+    }
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -53,27 +571,193 @@ pub fn get_config() -> Result<QuropConfig, Error> {
     Ok(cfg)
 }
 
-pub fn add_instance(
-    name: &str,
-    command: &str,
-    matcher: WindowMatcher,
-    class_name: Option<String>,
-) -> Result<(), Error> {
+pub fn add_instance(name: &str, instance: Instance) -> Result<(), Error> {
     let mut cfg = get_config()?;
     if cfg.instances.contains_key(name) {
         abort(&format!("{name} already exists"));
     }
-    cfg.instances.insert(
-        name.into(),
-        Instance {
-            command: command.into(),
-            matcher,
-            class_name,
-            window_delay_ms: None,
-            geometry: None,
-        },
-    );
+    if let Err(err) = instance.resolve(name) {
+        abort(&err.to_string());
+    }
+    cfg.instances.insert(name.into(), instance);
     let file_path = get_config_path()?;
     confy::store_path(file_path, cfg)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{TitleMatcher, WindowMatcher as Resolved};
+
+    /// Resolve an instance from its TOML table.
+    fn resolve(table: &str) -> Result<structs::Instance, Error> {
+        toml::from_str::<Instance>(table)
+            .map_err(|err| Error::InvalidInstance {
+                name: "test".into(),
+                reason: err.to_string(),
+            })?
+            .resolve("test")
+    }
+
+    /// Resolve an instance from its TOML table, expecting it to be rejected.
+    fn reason(table: &str) -> String {
+        resolve(table).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn matcher_defaults_to_the_process() {
+        let instance = resolve("command = 'wezterm'").unwrap();
+        assert!(matches!(instance.matcher, Resolved::ProcessId(None)));
+        assert!(matches!(instance.command, structs::Command::Shell(line) if line == "wezterm"));
+    }
+
+    #[test]
+    fn legacy_keys_build_the_named_matcher() {
+        let instance = resolve("command = 'x'\nmatcher = 'class'\nclass_name = 'Foo'").unwrap();
+        assert!(matches!(instance.matcher, Resolved::WmClass(class) if class == "Foo"));
+
+        let instance =
+            resolve("command = 'x'\nmatcher = 'title'\ntitle = '^Notes'\ntitle_match = 'regex'")
+                .unwrap();
+        assert!(matches!(
+            instance.matcher,
+            Resolved::Title(TitleMatcher::Regex(regex)) if regex.as_str() == "^Notes"
+        ));
+
+        assert!(reason("command = 'x'\nmatcher = 'class'").contains("'class_name' must be"));
+        assert!(reason("command = 'x'\nmatcher = 'role'").contains("'role' must be"));
+    }
+
+    #[test]
+    fn composite_rules_resolve_recursively() {
+        let instance = resolve(
+            r#"
+            command = "x"
+            matcher = { any = [
+                "process",
+                { class = "Foo" },
+                { all = [{ role = "main" }, { not = { title = { substring = "Prefs" } } }] },
+            ] }
+            "#,
+        )
+        .unwrap();
+        let Resolved::Any(rules) = instance.matcher else {
+            panic!("expected an `any` matcher");
+        };
+        assert!(matches!(rules[0], Resolved::ProcessId(None)));
+        assert!(matches!(&rules[1], Resolved::WmClass(class) if class == "Foo"));
+        let Resolved::All(all) = &rules[2] else {
+            panic!("expected an `all` matcher");
+        };
+        assert!(matches!(&all[0], Resolved::Role(role) if role == "main"));
+        assert!(matches!(
+            &all[1],
+            Resolved::Not(rule) if matches!(&**rule, Resolved::Title(TitleMatcher::Substring(_)))
+        ));
+    }
+
+    #[test]
+    fn rules_ignore_legacy_keys() {
+        let instance =
+            resolve("command = 'x'\nclass_name = 'Bar'\nmatcher = { class = 'Foo' }").unwrap();
+        assert!(matches!(instance.matcher, Resolved::WmClass(class) if class == "Foo"));
+    }
+
+    #[test]
+    fn malformed_rules_are_rejected() {
+        assert!(reason("command = 'x'\nmatcher = { any = ['class'] }")
+            .contains("the 'class' rule needs a value"));
+        assert!(
+            reason("command = 'x'\nmatcher = { class = 'Foo', role = 'bar' }")
+                .contains("combine rules with `all` or `any`")
+        );
+        assert!(
+            reason("command = 'x'\nmatcher = { title = { regex = '(' } }")
+                .contains("is not a valid regex")
+        );
+    }
+
+    #[test]
+    fn rules_survive_a_round_trip_through_toml() {
+        let table =
+            "command = 'x'\nmatcher = { any = ['process', { not = { sandbox = 'org.foo.App' } }] }";
+        let instance: Instance = toml::from_str(table).unwrap();
+        let written = toml::to_string(&instance).unwrap();
+        let reread: Instance = toml::from_str(&written).unwrap();
+        assert_eq!(
+            format!("{:?}", reread.rule("test").unwrap()),
+            format!("{:?}", instance.rule("test").unwrap())
+        );
+    }
+
+    #[test]
+    fn invalid_geometry_is_rejected() {
+        assert!(
+            reason("command = 'x'\ngeometry = { width = '50%', height = '0' }")
+                .contains("invalid height")
+        );
+        assert!(resolve("command = 'x'\ngeometry = { width = '800', height = '40%' }").is_ok());
+    }
+
+    #[test]
+    fn kill_signals_are_parsed() {
+        let instance = resolve("command = 'x'").unwrap();
+        assert_eq!(instance.kill_signal, Signal::TERM);
+        let instance = resolve("command = 'x'\nkill_signal = 'int'").unwrap();
+        assert_eq!(instance.kill_signal.number(), libc::SIGINT);
+        assert!(
+            reason("command = 'x'\nkill_signal = 'SIGSTOP'").contains("not a valid kill_signal")
+        );
+    }
+
+    /// Infer the app ID from a shell command.
+    fn app_id(command: &str) -> Result<Option<String>, String> {
+        flatpak_app_id(&Command::Shell(command.into()))
+    }
+
+    #[test]
+    fn flatpak_app_ids_are_inferred() {
+        let inferred = |command| app_id(command).unwrap().unwrap();
+        assert_eq!(
+            inferred("flatpak run md.obsidian.Obsidian"),
+            "md.obsidian.Obsidian"
+        );
+        assert_eq!(
+            inferred("/usr/bin/flatpak run --user org.foo.App --new"),
+            "org.foo.App"
+        );
+        assert_eq!(
+            inferred("flatpak run --branch stable org.foo.App"),
+            "org.foo.App"
+        );
+        assert_eq!(
+            inferred("flatpak run --branch=beta org.foo.App"),
+            "org.foo.App"
+        );
+        assert_eq!(
+            inferred("flatpak run --command sh --filesystem home app/org.foo.App/x86_64/stable"),
+            "org.foo.App"
+        );
+        assert_eq!(app_id("wezterm start"), Ok(None));
+        assert_eq!(app_id("flatpak install org.foo.App"), Ok(None));
+    }
+
+    #[test]
+    fn ambiguous_flatpak_commands_need_an_app_id() {
+        assert!(app_id("flatpak run --unknown stable org.foo.App").is_err());
+        assert!(app_id("flatpak run --user").is_err());
+        assert!(reason(
+            "command = 'flatpak run --unknown stable org.foo.App'\nmatcher = 'sandbox'"
+        )
+        .contains("'app_id' must be specified"));
+        let instance = resolve(
+            "command = 'flatpak run --unknown stable org.foo.App'\nmatcher = 'sandbox'\napp_id = 'org.foo.App'",
+        )
+        .unwrap();
+        assert!(matches!(
+            instance.matcher,
+            Resolved::Sandbox { pid: None, app_id: Some(app_id) } if app_id == "org.foo.App"
+        ));
+    }
+}
@@ -0,0 +1,680 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fs::{create_dir_all, remove_file},
+    io::BufReader,
+    os::unix::{
+        net::{UnixListener, UnixStream},
+        process::CommandExt,
+    },
+    panic,
+    path::PathBuf,
+    process,
+    sync::{mpsc, Arc, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use directories::ProjectDirs;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config,
+    errors::Error,
+    instance::{program_thread, Message},
+    ipc,
+    structs::{
+        AdoptPolicy, Command, Context, Geometry, Instance, ReconnectPolicy, RestartPolicy, Signal,
+        WindowMatcher,
+    },
+    x11,
+};
+
+/// Requests handled by the daemon's main thread.
+enum Control {
+    /// A thread has terminated unexpectedly.
+    ThreadExited,
+    /// Shut down, optionally killing every program. The client, if any, is told once done.
+    Shutdown {
+        kill: bool,
+        client: Option<UnixStream>,
+    },
+}
+
+struct Sentinel {
+    control: mpsc::Sender<Control>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        warn!("A thread has terminated or panicked");
+        let _ = self.control.send(Control::ThreadExited);
+    }
+}
+
+/// The daemon's handle on one of its managed instances.
+#[derive(Clone)]
+struct InstanceHandle {
+    tx: mpsc::Sender<Message>,
+    ctx: Arc<RwLock<Context>>,
+    /// Whether the instance was captured at runtime, rather than configured.
+    captured: bool,
+}
+
+type Instances = Arc<RwLock<BTreeMap<String, InstanceHandle>>>;
+
+/// The shortest and longest waits between attempts to reconnect to the X server.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Get the path of the daemon's control socket, creating its parent directory.
+fn get_socket_path() -> Result<PathBuf, Error> {
+    let dir = match ProjectDirs::from("net", "arusahni", "qurop")
+        .expect("could not find project dirs")
+        .runtime_dir()
+    {
+        Some(runtime_dir) => runtime_dir.to_path_buf(),
+        None => env::temp_dir().join("qurop"),
+    };
+    debug!("Attempting to create project dir: {:?}", dir);
+    create_dir_all(&dir)?;
+    Ok(dir.join("daemon.sock"))
+}
+
+/// Connect to the socket of the running daemon, if there is one.
+pub(crate) fn connect() -> Result<Option<UnixStream>, Error> {
+    let socket_path = get_socket_path()?;
+    match UnixStream::connect(socket_path) {
+        Ok(stream) => Ok(Some(stream)),
+        Err(err) => match err.kind() {
+            std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused => Ok(None),
+            _ => Err(err.into()),
+        },
+    }
+}
+
+/// Create the daemon's control socket, unless a daemon is already listening on it.
+pub(crate) fn bind() -> Result<Option<UnixListener>, Error> {
+    let socket_path = get_socket_path()?;
+    let socket_exists = socket_path.exists();
+    debug!("Socket: {:?} | Exists? {}", socket_path, socket_exists);
+    if socket_exists {
+        debug!("Connecting to socket");
+        match UnixStream::connect(&socket_path) {
+            Ok(_) => return Ok(None),
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+                warn!("Can't connect to socket. Assuming stale, starting new daemon.");
+                remove_file(&socket_path)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    info!("Creating socket");
+    Ok(Some(UnixListener::bind(&socket_path)?))
+}
+
+/// Start the daemon in the background, detached from the calling process's session.
+pub(crate) fn spawn_detached(
+    persist_verbosity: Option<tracing::Level>,
+) -> Result<process::Child, Error> {
+    let mut command = process::Command::new(env::current_exe()?);
+    if let Some(level) = persist_verbosity {
+        command.arg(format!("--persist-verbosity={level}"));
+    }
+    command
+        .arg("daemon")
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null());
+    // SAFETY: `setsid` is async-signal-safe, and is the only call made between fork and exec.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let daemon = command.spawn()?;
+    info!("Spawned daemon with PID: {}", daemon.id());
+    Ok(daemon)
+}
+
+/// Wait for the spawned daemon to start accepting requests, returning once it has acknowledged a
+/// ping. Fails early if the daemon exits.
+pub(crate) fn wait_until_ready(
+    daemon: &mut process::Child,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    loop {
+        // Checked before connecting, so that a daemon which exited because another had already
+        // started leaves the other to be connected to.
+        let exited = daemon.try_wait()?;
+        if let Some(stream) = connect()? {
+            let response = ipc::request(stream, &ipc::Request::new(ipc::Action::Ping, None))?;
+            if response.success {
+                debug!("Daemon ready after {:?}", start.elapsed());
+                return Ok(());
+            }
+        }
+        if let Some(status) = exited {
+            return Err(Error::DaemonExited(status));
+        }
+        if start.elapsed() > timeout {
+            return Err(Error::DaemonUnavailable);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn reply(stream: &UnixStream, response: &ipc::Response) {
+    if let Err(err) = ipc::send(stream, response) {
+        warn!("Couldn't respond to client: {}", err);
+    }
+}
+
+/// Hand an action to an instance's program thread and wait for its response.
+fn forward(handle: &InstanceHandle, action: ipc::Action) -> ipc::Response {
+    let (response_tx, response_rx) = mpsc::channel();
+    if handle
+        .tx
+        .send(Message::Request(action, response_tx))
+        .is_err()
+    {
+        return ipc::Response::error("The instance is no longer being managed");
+    }
+    response_rx
+        .recv()
+        .unwrap_or_else(|_| ipc::Response::error("The instance stopped before responding"))
+}
+
+/// Route a request to the instance it targets, or handle it daemon-wide.
+/// Get the handle of a managed instance. Instances added to the config since the daemon started
+/// are loaded on first use.
+fn get_instance(
+    name: &str,
+    instances: &Instances,
+    display: &x11::DisplayHandle,
+    subscribers: &ipc::Subscribers,
+) -> Result<Option<InstanceHandle>, Error> {
+    if let Some(handle) = instances.read().unwrap().get(name) {
+        return Ok(Some(handle.clone()));
+    }
+    let Some(instance) = config::get_config()?.instances.remove(name) else {
+        return Ok(None);
+    };
+    let instance = instance.resolve(name)?;
+    let mut handles = instances.write().unwrap();
+    // Another client may have loaded it in the meantime.
+    if let Some(handle) = handles.get(name) {
+        return Ok(Some(handle.clone()));
+    }
+    info!("[{}] Loaded from the config", name);
+    let handle = spawn_configured(instance, instances, display, subscribers);
+    handles.insert(name.into(), handle.clone());
+    Ok(Some(handle))
+}
+
+fn dispatch(
+    instances: &Instances,
+    display: &x11::DisplayHandle,
+    subscribers: &ipc::Subscribers,
+    request: &ipc::Request,
+) -> ipc::Response {
+    if request.action == ipc::Action::List {
+        // Don't hold the lock while waiting on the instances.
+        let handles: Vec<_> = instances.read().unwrap().values().cloned().collect();
+        let statuses = handles
+            .iter()
+            .filter_map(|handle| forward(handle, ipc::Action::Status).status)
+            .collect();
+        return ipc::Response::instances(statuses);
+    }
+    let Some(name) = &request.instance else {
+        return ipc::Response::error("An instance name is required");
+    };
+    let handle = match request.action {
+        // Once shut down, the instance is no longer managed, so forget it along with its thread.
+        ipc::Action::Shutdown { .. } => instances.write().unwrap().remove(name),
+        _ => match get_instance(name, instances, display, subscribers) {
+            Ok(handle) => handle,
+            Err(err) => return ipc::Response::error(err.to_string()),
+        },
+    };
+    match handle {
+        Some(handle) => forward(&handle, request.action),
+        None => ipc::Response::error(format!("No configuration found for '{name}'")),
+    }
+}
+
+/// Start managing the active window as a new instance, hiding it until it's next toggled.
+fn capture(
+    name: &str,
+    instances: &Instances,
+    display: &x11::DisplayHandle,
+    subscribers: &ipc::Subscribers,
+) -> Result<ipc::Response, Error> {
+    let Some(window_id) = display
+        .with(|x| x.get_active_window())?
+        .filter(|id| *id != 0)
+    else {
+        return Ok(ipc::Response::error("No window is active"));
+    };
+    let mut handles = instances.write().unwrap();
+    if handles.contains_key(name) {
+        return Ok(ipc::Response::error(format!(
+            "'{name}' is already being managed"
+        )));
+    }
+    if let Some(owner) = handles
+        .iter()
+        .find(|(_, handle)| handle.ctx.read().unwrap().owns(window_id))
+        .map(|(owner, _)| owner)
+    {
+        return Ok(ipc::Response::error(format!(
+            "The active window already belongs to '{owner}'"
+        )));
+    }
+    let pid = display.with(|x| {
+        x.tag_window(window_id, name)?;
+        x.get_window_pid(window_id)
+    })?;
+    let instance = Instance {
+        name: name.into(),
+        command: Command::Shell(String::new()),
+        env: BTreeMap::new(),
+        unset_env: Vec::new(),
+        cwd: None,
+        matcher: WindowMatcher::Window(window_id),
+        window_delay: None,
+        geometry: Geometry::default(),
+        on_reconnect: ReconnectPolicy::Rediscover,
+        // There's nothing to launch, so only ever look for the window itself.
+        adopt: AdoptPolicy::AdoptOnly,
+        restart: RestartPolicy::Never,
+        // Captured windows weren't started by qurop, so are never killed.
+        kill_signal: Signal::default(),
+        kill_timeout: Duration::ZERO,
+    };
+    let ctx = Context {
+        matcher: instance.matcher.clone(),
+        window_id: Some(window_id),
+        pid,
+        exit_status: None,
+        started: Instant::now(),
+        adopted: true,
+        windows: BTreeSet::new(),
+        expected_unmaps: BTreeSet::new(),
+    };
+    let handle = spawn_instance(instance, ctx, true, instances, display, subscribers);
+    handles.insert(name.into(), handle.clone());
+    drop(handles);
+    info!("[{}] Captured window {}", name, window_id);
+    subscribers.publish(name, ipc::EventKind::Rediscovered { window_id });
+    Ok(forward(&handle, ipc::Action::Hide))
+}
+
+/// Stop managing a captured instance, leaving its window where the user can see it.
+fn release(name: &str, instances: &Instances) -> ipc::Response {
+    let mut handles = instances.write().unwrap();
+    let handle = match handles.get(name) {
+        Some(handle) if handle.captured => handles.remove(name).unwrap(),
+        Some(_) => {
+            return ipc::Response::error(format!(
+            "'{name}' is configured, not captured. Use `qurop shutdown {name}` to stop managing it"
+        ))
+        }
+        None => return ipc::Response::error(format!("No captured instance named '{name}'")),
+    };
+    drop(handles);
+    info!("[{}] Releasing captured window", name);
+    forward(&handle, ipc::Action::Shutdown { kill: false })
+}
+
+fn handle_client(
+    stream: UnixStream,
+    instances: &Instances,
+    display: &x11::DisplayHandle,
+    subscribers: &ipc::Subscribers,
+    control: &mpsc::Sender<Control>,
+) -> Result<(), Error> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request = match ipc::receive_request(&mut reader) {
+        Ok(Some(request)) => request,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            warn!("Unrecognized message: {}", err);
+            reply(&stream, &ipc::Response::error(err.to_string()));
+            return Ok(());
+        }
+    };
+    debug!("Received: {:?}", request);
+    match request.action {
+        ipc::Action::Ping => reply(&stream, &ipc::Response::ok(None)),
+        ipc::Action::Shutdown { kill } if request.instance.is_none() => {
+            let _ = control.send(Control::Shutdown {
+                kill,
+                client: Some(stream),
+            });
+        }
+        ipc::Action::Capture | ipc::Action::Release if request.instance.is_none() => reply(
+            &stream,
+            &ipc::Response::error("An instance name is required"),
+        ),
+        ipc::Action::Capture => {
+            let name = request.instance.as_deref().unwrap_or_default();
+            let response = capture(name, instances, display, subscribers)
+                .unwrap_or_else(|err| ipc::Response::error(err.to_string()));
+            reply(&stream, &response);
+        }
+        ipc::Action::Release => {
+            let name = request.instance.as_deref().unwrap_or_default();
+            reply(&stream, &release(name, instances));
+        }
+        ipc::Action::Subscribe => {
+            let found = match &request.instance {
+                Some(name) => get_instance(name, instances, display, subscribers)
+                    .map(|handle| handle.is_some()),
+                None => Ok(true),
+            };
+            match found {
+                Ok(true) => {
+                    reply(&stream, &ipc::Response::ok(None));
+                    subscribers.add(stream, request.instance)?;
+                }
+                Ok(false) => reply(
+                    &stream,
+                    &ipc::Response::error(format!(
+                        "No configuration found for '{}'",
+                        request.instance.unwrap_or_default()
+                    )),
+                ),
+                Err(err) => reply(&stream, &ipc::Response::error(err.to_string())),
+            }
+        }
+        _ => reply(
+            &stream,
+            &dispatch(instances, display, subscribers, &request),
+        ),
+    }
+    Ok(())
+}
+
+fn handle_socket_messages(
+    listener: UnixListener,
+    instances: Instances,
+    display: x11::DisplayHandle,
+    subscribers: ipc::Subscribers,
+    control: mpsc::Sender<Control>,
+) -> Result<(), Error> {
+    loop {
+        let (stream, addr) = listener.accept()?;
+        debug!("Accepting stream from: {:?}", addr);
+        let instances = Arc::clone(&instances);
+        let display = display.clone();
+        let subscribers = subscribers.clone();
+        let control = control.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle_client(stream, &instances, &display, &subscribers, &control) {
+                warn!("Couldn't handle client: {}", err);
+            }
+        });
+    }
+}
+
+/// Tell every instance that doesn't own the newly-active window that it has lost focus.
+fn hide_inactive(instances: &Instances, active_window: u32) {
+    for handle in instances.read().unwrap().values() {
+        let ctx = handle.ctx.read().unwrap();
+        if let Some(window_id) = ctx.window_id.filter(|_| !ctx.owns(active_window)) {
+            debug!("sending focus lost: {} != {}", active_window, window_id);
+            let _ = handle.tx.send(Message::FocusLost(active_window));
+        }
+    }
+}
+
+/// Pass a message about a window on to the instance that owns it, if any.
+fn notify_owner(instances: &Instances, window_id: u32, message: Message) {
+    let instances = instances.read().unwrap();
+    let owner = instances
+        .values()
+        .find(|handle| handle.ctx.read().unwrap().owns(window_id));
+    if let Some(handle) = owner {
+        let _ = handle.tx.send(message);
+    }
+}
+
+/// Watch the active window, reconnecting with backoff whenever the X server goes away. Once
+/// reconnected, every instance is told so it can find its window again.
+fn watch_windows(display: &x11::DisplayHandle, instances: &Instances) -> Result<(), Error> {
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+    let mut reconnecting = false;
+    loop {
+        let x = match display.get() {
+            Ok(x) => x,
+            Err(err) if reconnecting => {
+                warn!(
+                    "Couldn't reconnect to the X server ({}). Retrying in {:?}",
+                    err, backoff
+                );
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if reconnecting {
+            info!("Reconnected to the X server");
+            backoff = RECONNECT_BACKOFF_MIN;
+            for handle in instances.read().unwrap().values() {
+                let _ = handle.tx.send(Message::Reconnected);
+            }
+        }
+        match x.handle_window(display.changes(), |event| match event {
+            x11::WindowEvent::Activated(active_window) => hide_inactive(instances, active_window),
+            x11::WindowEvent::Unmapped(window_id) => {
+                notify_owner(instances, window_id, Message::Unmapped(window_id))
+            }
+            x11::WindowEvent::Destroyed(window_id) => {
+                notify_owner(instances, window_id, Message::Destroyed(window_id))
+            }
+        }) {
+            Err(err) if x11::is_disconnect(&err) => {
+                error!("Lost the connection to the X server: {}", err);
+                display.reset(&x);
+                reconnecting = true;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Stop accepting requests and stop managing every instance.
+fn shutdown(instances: &Instances, kill: bool) -> Result<(), Error> {
+    info!("Shutting down");
+    match remove_file(get_socket_path()?) {
+        Ok(()) => debug!("Removed socket"),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    let handles = instances.read().unwrap().clone();
+    for (name, handle) in handles.iter() {
+        let response = forward(handle, ipc::Action::Shutdown { kill });
+        if let Some(err) = response.error {
+            debug!("[{}] {}", name, err);
+        }
+    }
+    Ok(())
+}
+
+/// Start a program thread for a configured instance, whose program hasn't been launched yet.
+fn spawn_configured(
+    instance: Instance,
+    instances: &Instances,
+    display: &x11::DisplayHandle,
+    subscribers: &ipc::Subscribers,
+) -> InstanceHandle {
+    let ctx = Context {
+        matcher: instance.matcher.clone(),
+        window_id: None,
+        pid: None,
+        exit_status: None,
+        started: Instant::now(),
+        adopted: false,
+        windows: BTreeSet::new(),
+        expected_unmaps: BTreeSet::new(),
+    };
+    spawn_instance(instance, ctx, false, instances, display, subscribers)
+}
+
+/// Start a program thread for the instance. If the thread panics, the instance is dropped from
+/// `instances`, leaving the daemon to carry on managing the others.
+fn spawn_instance(
+    instance: Instance,
+    ctx: Context,
+    captured: bool,
+    instances: &Instances,
+    display: &x11::DisplayHandle,
+    subscribers: &ipc::Subscribers,
+) -> InstanceHandle {
+    let (tx, rx) = mpsc::channel::<Message>();
+    let ctx = Arc::new(RwLock::new(ctx));
+    let program_tx = tx.clone();
+    let program_ctx = Arc::clone(&ctx);
+    let program_instances = Arc::clone(instances);
+    let program_subscribers = subscribers.clone();
+    let program_display = display.clone();
+    thread::spawn(move || {
+        let name = instance.name.clone();
+        match panic::catch_unwind(|| {
+            program_thread(
+                rx,
+                program_tx,
+                &program_display,
+                instance,
+                &program_ctx,
+                &program_subscribers,
+            );
+        }) {
+            Ok(()) => info!("[{}] Program thread completed", name),
+            Err(err) => {
+                error!(?err, "[{}] Program thread panicked", name);
+                let mut handles = program_instances.write().unwrap();
+                if handles
+                    .get(&name)
+                    .is_some_and(|handle| Arc::ptr_eq(&handle.ctx, &program_ctx))
+                {
+                    handles.remove(&name);
+                }
+                drop(handles);
+                // Don't leave the windows hidden (and undecorated) with nothing to bring them back.
+                let (window_id, windows) = {
+                    let ctx = program_ctx
+                        .read()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                    (ctx.window_id, ctx.windows.clone())
+                };
+                if let Some(window_id) = window_id {
+                    if let Err(err) = program_display.with(|x| {
+                        x.restore_window(window_id)?;
+                        for related_id in &windows {
+                            match x.restore_window(*related_id) {
+                                Ok(()) | Err(Error::WindowGone(_)) => {}
+                                Err(err) => return Err(err),
+                            }
+                        }
+                        Ok(())
+                    }) {
+                        error!("[{}] Couldn't restore window: {}", name, err);
+                    }
+                }
+            }
+        };
+    });
+    InstanceHandle { tx, ctx, captured }
+}
+
+/// Run the daemon, managing every given instance until asked to shut down or a thread terminates.
+pub(crate) fn run(listener: UnixListener, instances: Vec<Instance>) -> Result<(), Error> {
+    let (control_tx, control_rx) = mpsc::channel::<Control>();
+    let subscribers = ipc::Subscribers::default();
+    let display = x11::DisplayHandle::default();
+    let handles: Instances = Arc::default();
+    for instance in instances {
+        let name = instance.name.clone();
+        let handle = spawn_configured(instance, &handles, &display, &subscribers);
+        handles.write().unwrap().insert(name, handle);
+    }
+    info!("Managing {} instance(s)", handles.read().unwrap().len());
+    let instances = handles;
+
+    let socket_instances = Arc::clone(&instances);
+    let socket_control = control_tx.clone();
+    let socket_display = display.clone();
+    thread::spawn(move || {
+        let _sentinel = Sentinel {
+            control: socket_control.clone(),
+        };
+        match panic::catch_unwind(|| {
+            if let Err(err) = handle_socket_messages(
+                listener,
+                socket_instances,
+                socket_display,
+                subscribers,
+                socket_control,
+            ) {
+                error!("Socket failed: {}", err);
+            }
+        }) {
+            Ok(()) => info!("Socket thread completed"),
+            Err(err) => error!(?err, "Socket thread panicked"),
+        };
+    });
+    let window_instances = Arc::clone(&instances);
+    let wm_control = control_tx.clone();
+    thread::spawn(move || {
+        let _sentinel = Sentinel {
+            control: wm_control,
+        };
+        match panic::catch_unwind(|| {
+            if let Err(err) = watch_windows(&display, &window_instances) {
+                error!("Couldn't watch the active window: {}", err);
+            }
+        }) {
+            Ok(()) => info!("WM thread completed"),
+            Err(err) => error!(?err, "WM thread panicked"),
+        };
+    });
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let signal_control = control_tx.clone();
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Received signal {}", signal);
+            let _ = signal_control.send(Control::Shutdown {
+                kill: false,
+                client: None,
+            });
+        }
+    });
+
+    let (kill, client) = match control_rx.recv() {
+        Ok(Control::Shutdown { kill, client }) => (kill, client),
+        Ok(Control::ThreadExited) | Err(_) => (false, None),
+    };
+    let result = shutdown(&instances, kill);
+    if let Some(client) = client {
+        reply(
+            &client,
+            &match &result {
+                Ok(()) => ipc::Response::ok(None),
+                Err(err) => ipc::Response::error(err.to_string()),
+            },
+        );
+    }
+    result
+}
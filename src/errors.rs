@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("An IO error has occurred: {source}")]
@@ -7,9 +9,41 @@ pub enum Error {
     },
     #[error("The window is not found")]
     WindowNotFound,
+    #[error("The window {0:#x} no longer exists")]
+    WindowGone(u32),
+    #[error("The window didn't appear within {0:?}")]
+    Timeout(Duration),
+    #[error("Couldn't connect to the X server: {source}")]
+    XConnect {
+        #[from]
+        source: x11rb::errors::ConnectError,
+    },
+    #[error("The connection to the X server failed: {source}")]
+    XConnection {
+        #[from]
+        source: x11rb::errors::ConnectionError,
+    },
+    #[error("The X server returned an error: {source}")]
+    XReply {
+        #[from]
+        source: x11rb::errors::ReplyError,
+    },
+    #[error("The qurop daemon didn't start in time")]
+    DaemonUnavailable,
+    #[error("The qurop daemon exited on startup ({0}). Run `qurop daemon` to see why")]
+    DaemonExited(std::process::ExitStatus),
+    #[error("Invalid configuration for '{name}': {reason}")]
+    InvalidInstance { name: String, reason: String },
     #[error("Configuration error: {source}")]
     Config {
         #[from]
         source: confy::ConfyError,
     },
+    #[error("Unsupported protocol version {found} (expected {expected})")]
+    UnsupportedVersion { found: u32, expected: u32 },
+    #[error("Malformed message: {source}")]
+    Protocol {
+        #[from]
+        source: serde_json::Error,
+    },
 }
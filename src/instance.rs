@@ -0,0 +1,909 @@
+use std::{
+    collections::BTreeSet,
+    io, mem,
+    os::unix::process::CommandExt,
+    process::{self, ExitStatus},
+    sync::{mpsc, Arc, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    errors::Error,
+    ipc,
+    structs::{AdoptPolicy, Command, Context, Instance, ReconnectPolicy, Signal, WindowMatcher},
+    x11,
+};
+
+/// Work items handled by an instance's program thread.
+pub(crate) enum Message {
+    /// A client request, along with the channel on which to send the response.
+    Request(ipc::Action, mpsc::Sender<ipc::Response>),
+    /// Another window became active, so hide the instance unless the window belongs to it.
+    FocusLost(u32),
+    /// The daemon has reconnected to the X server, so any known window ID is stale.
+    Reconnected,
+    /// One of the instance's windows was unmapped.
+    Unmapped(u32),
+    /// One of the instance's windows was destroyed.
+    Destroyed(u32),
+    /// The program has exited, and is ready to be reaped.
+    Exited,
+}
+
+/// How often to check whether the program has stopped after signalling it.
+const KILL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long to wait for a window to appear before giving up.
+const WINDOW_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for the window watcher before looking for the window regardless.
+const WINDOW_RESCAN_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The shortest and longest waits before automatically restarting a program that exited.
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// How many times a program that keeps exiting soon after starting is restarted before giving up.
+const RESTART_LIMIT: u32 = 5;
+
+/// How long a program must run before its exit no longer counts towards the restart limit.
+const RESTART_STABLE_AFTER: Duration = Duration::from_secs(30);
+
+/// Tracks automatic restarts of the program, backing off and giving up if it keeps exiting.
+#[derive(Default)]
+struct Restarts {
+    /// Restarts since the program last ran for `RESTART_STABLE_AFTER`.
+    attempts: u32,
+    /// When the current program was started.
+    started: Option<Instant>,
+    /// When the program is next due to be restarted.
+    due: Option<Instant>,
+}
+
+impl Restarts {
+    /// Schedule the program to be restarted, unless it's stuck in a crash loop. Returns the delay
+    /// before the restart, if one was scheduled.
+    fn schedule(&mut self, name: &str) -> Option<Duration> {
+        if self
+            .started
+            .is_some_and(|started| started.elapsed() >= RESTART_STABLE_AFTER)
+        {
+            self.attempts = 0;
+        }
+        if self.attempts >= RESTART_LIMIT {
+            warn!(
+                "[{}] Program keeps exiting, giving up after {} restarts",
+                name, self.attempts
+            );
+            return None;
+        }
+        let delay = RESTART_BACKOFF_MIN
+            .saturating_mul(1 << self.attempts)
+            .min(RESTART_BACKOFF_MAX);
+        self.attempts += 1;
+        info!("[{}] Restarting in {:?}", name, delay);
+        self.due = Some(Instant::now() + delay);
+        Some(delay)
+    }
+}
+
+/// Find and position the window, waiting for it to appear if needed.
+fn block_for_window(
+    display: &x11::DisplayHandle,
+    matcher: &WindowMatcher,
+    instance: &Instance,
+) -> Result<u32, Error> {
+    trace!("blocking for window {:?}", matcher);
+    let mut attempts = 0;
+    let start = Instant::now();
+    // Taken before each search, so changes made while searching aren't missed.
+    let mut generation = display.changes().generation();
+    loop {
+        attempts += 1;
+        let found = display.with(|x| {
+            let window_id = x.map_qurop_window(&instance.name, matcher)?;
+            x.position_window(window_id, instance)?;
+            Ok(window_id)
+        });
+        match found {
+            Ok(window_id) => return Ok(window_id),
+            Err(Error::WindowNotFound | Error::WindowGone(_)) => {
+                trace!("window not found");
+            }
+            Err(err) => return Err(err),
+        }
+        let Some(remaining) = WINDOW_TIMEOUT.checked_sub(start.elapsed()) else {
+            warn!("could not find window after {} attempts", attempts);
+            return Err(Error::Timeout(WINDOW_TIMEOUT));
+        };
+        generation = display
+            .changes()
+            .wait(generation, remaining.min(WINDOW_RESCAN_INTERVAL));
+    }
+}
+
+/// Start the program, recording its PID.
+fn spawn_program(instance: &Instance, ctx: &Arc<RwLock<Context>>) -> Result<process::Child, Error> {
+    let mut command = match &instance.command {
+        Command::Shell(line) => {
+            let mut command = process::Command::new("sh");
+            command.arg("-c").arg(line);
+            command
+        }
+        Command::Exec { program, args } => {
+            let mut command = process::Command::new(program);
+            command.args(args);
+            command
+        }
+    };
+    for name in &instance.unset_env {
+        command.env_remove(name);
+    }
+    command.envs(&instance.env);
+    if let Some(cwd) = &instance.cwd {
+        command.current_dir(cwd);
+    }
+    // In its own process group, so it can be stopped along with anything it starts.
+    let program = command.process_group(0).spawn()?;
+    info!("[{}] Started PID: {}", instance.name, program.id());
+    let write_ctx = &mut ctx.write().unwrap();
+    write_ctx.pid = Some(program.id());
+    write_ctx.exit_status = None;
+    write_ctx.set_window(None);
+    if write_ctx.matcher.set_pid(program.id()) {
+        trace!("[{}] Set a new PID {}", instance.name, program.id());
+    }
+    Ok(program)
+}
+
+/// Wait in the background for the program to exit, then tell the program thread. The program is
+/// left for the program thread to reap, so its exit status isn't lost.
+fn watch_exit(pid: u32, tx: mpsc::Sender<Message>) {
+    thread::spawn(move || loop {
+        // SAFETY: `siginfo_t` is plain data, so zeroed memory is a valid value to write into.
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        // SAFETY: `info` is valid for writes, and `WNOWAIT` leaves the child to be reaped later.
+        let result =
+            unsafe { libc::waitid(libc::P_PID, pid, &mut info, libc::WEXITED | libc::WNOWAIT) };
+        if result == 0 {
+            let _ = tx.send(Message::Exited);
+            return;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            // Most likely the program was already reaped after being killed.
+            trace!("Stopped watching PID {}: {}", pid, err);
+            return;
+        }
+    });
+}
+
+/// Reap the program if it has exited, recording how it exited and letting subscribers know.
+fn reap(
+    program: &mut Option<process::Child>,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+    events: &ipc::Subscribers,
+) -> Result<Option<ExitStatus>, Error> {
+    let Some(status) = program
+        .as_mut()
+        .map(process::Child::try_wait)
+        .transpose()?
+        .flatten()
+    else {
+        return Ok(None);
+    };
+    *program = None;
+    info!("[{}] Program exited ({})", instance.name, status);
+    ctx.write().unwrap().exit_status = Some(status);
+    events.publish(
+        &instance.name,
+        ipc::EventKind::Exited {
+            code: status.code(),
+        },
+    );
+    Ok(Some(status))
+}
+
+/// Start the program again after it exited, leaving its window hidden until the next show.
+fn restart(
+    display: &x11::DisplayHandle,
+    program: &mut Option<process::Child>,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+    events: &ipc::Subscribers,
+) -> Result<(), Error> {
+    let child = spawn_program(instance, ctx)?;
+    events.publish(
+        &instance.name,
+        ipc::EventKind::Restarted { pid: child.id() },
+    );
+    *program = Some(child);
+    let window_id = discover_window(display, instance, ctx)?;
+    hide_windows(display, window_id, instance, ctx)?;
+    events.publish(&instance.name, ipc::EventKind::Rediscovered { window_id });
+    Ok(())
+}
+
+/// Find, show, and position the program's window, recording its ID.
+fn discover_window(
+    display: &x11::DisplayHandle,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+) -> Result<u32, Error> {
+    // Not held while waiting, as the window watcher needs it to report other windows.
+    let matcher = {
+        let mut write_ctx = ctx.write().unwrap();
+        write_ctx.set_window(None);
+        write_ctx.matcher.clone()
+    };
+    let window_id = block_for_window(display, &matcher, instance)?;
+    ctx.write().unwrap().set_window(Some(window_id));
+    trace!("[{}] Set a new Window ID {}", instance.name, window_id);
+    Ok(window_id)
+}
+
+/// Send a signal to every process in the group.
+fn signal_group(pgid: u32, signal: libc::c_int) -> io::Result<()> {
+    // SAFETY: `killpg` has no memory safety requirements.
+    if unsafe { libc::killpg(pgid as libc::pid_t, signal) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Determine if any process is left in the group.
+fn group_exists(pgid: u32) -> bool {
+    match signal_group(pgid, 0) {
+        Ok(()) => true,
+        Err(err) => err.raw_os_error() != Some(libc::ESRCH),
+    }
+}
+
+/// Signal the program's process group, killing it outright if it hasn't stopped within the
+/// instance's timeout. Returns the program's exit status, and whether it had to be killed.
+fn stop_program(
+    child: &mut process::Child,
+    instance: &Instance,
+) -> Result<(ExitStatus, bool), Error> {
+    let pgid = child.id();
+    info!(
+        "[{}] Sending {} to process group {}",
+        instance.name, instance.kill_signal, pgid
+    );
+    signal_group(pgid, instance.kill_signal.number())?;
+    let deadline = Instant::now() + instance.kill_timeout;
+    let mut status = None;
+    loop {
+        if status.is_none() {
+            status = child.try_wait()?;
+        }
+        // The shell may exit before the programs it started.
+        if let Some(status) = status.filter(|_| !group_exists(pgid)) {
+            return Ok((status, false));
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(KILL_POLL_INTERVAL);
+    }
+    warn!(
+        "[{}] Still running after {:?}, sending {}",
+        instance.name,
+        instance.kill_timeout,
+        Signal::KILL
+    );
+    match signal_group(pgid, Signal::KILL.number()) {
+        Err(err) if err.raw_os_error() != Some(libc::ESRCH) => return Err(err.into()),
+        _ => {}
+    }
+    Ok((child.wait()?, true))
+}
+
+/// Stop the program, if it's running.
+fn kill_program(
+    program: &mut Option<process::Child>,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+    events: &ipc::Subscribers,
+) -> Result<ipc::Response, Error> {
+    let Some(mut child) = program.take() else {
+        let mut write_ctx = ctx.write().unwrap();
+        if write_ctx.adopted {
+            return Ok(ipc::Response::error(format!(
+                "'{}' wasn't started by qurop, so it can't be killed",
+                instance.name
+            )));
+        }
+        // Forget that it exited, so the next show starts it regardless of its restart policy.
+        if write_ctx.exit_status.take().is_some() {
+            return Ok(ipc::Response::ok(Some(false)));
+        }
+        return Ok(ipc::Response::error(format!(
+            "'{}' is not running",
+            instance.name
+        )));
+    };
+    match stop_program(&mut child, instance) {
+        Ok((status, killed)) => {
+            debug!("[{}] Program exited ({})", instance.name, status);
+            let mut write_ctx = ctx.write().unwrap();
+            write_ctx.set_window(None);
+            write_ctx.pid = None;
+            events.publish(
+                &instance.name,
+                ipc::EventKind::Exited {
+                    code: status.code(),
+                },
+            );
+            let message = if killed {
+                format!(
+                    "'{}' didn't stop within {:?} of {}, so it was killed",
+                    instance.name, instance.kill_timeout, instance.kill_signal
+                )
+            } else {
+                format!("Stopped '{}' with {}", instance.name, instance.kill_signal)
+            };
+            Ok(ipc::Response::message(Some(false), message))
+        }
+        Err(err) => {
+            *program = Some(child);
+            Err(err)
+        }
+    }
+}
+
+/// Take over an existing window for the instance, if there is one.
+fn adopt(
+    display: &x11::DisplayHandle,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+    events: &ipc::Subscribers,
+) -> Result<Option<u32>, Error> {
+    let matcher = ctx.read().unwrap().matcher.clone();
+    let found = display.with(|x| {
+        let Some(window_id) = x.get_qurop_window_id(&instance.name, &matcher)? else {
+            return Ok(None);
+        };
+        x.tag_window(window_id, &instance.name)?;
+        Ok(Some((window_id, x.get_window_pid(window_id)?)))
+    })?;
+    let Some((window_id, pid)) = found else {
+        debug!("[{}] No window to adopt", instance.name);
+        return Ok(None);
+    };
+    info!("[{}] Adopted window {}", instance.name, window_id);
+    let mut write_ctx = ctx.write().unwrap();
+    write_ctx.set_window(Some(window_id));
+    write_ctx.pid = pid;
+    write_ctx.adopted = true;
+    events.publish(&instance.name, ipc::EventKind::Rediscovered { window_id });
+    Ok(Some(window_id))
+}
+
+/// Hide the main window along with every other window belonging to the instance, including any
+/// that have appeared since it was shown.
+fn hide_windows(
+    display: &x11::DisplayHandle,
+    window_id: u32,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+) -> Result<(), Error> {
+    let (matcher, owned) = {
+        let read_ctx = ctx.read().unwrap();
+        (read_ctx.matcher.clone(), read_ctx.owned_windows())
+    };
+    // Only mapped windows report being unmapped, so only they're expected to.
+    let (windows, unmapped) = display.with(|x| {
+        let mut related = owned.clone();
+        related.extend(x.find_related_windows(&instance.name, &matcher, &owned)?);
+        related.remove(&window_id);
+        let mut hidden = BTreeSet::new();
+        let mut unmapped = BTreeSet::new();
+        for related_id in related {
+            let hide = || {
+                x.tag_related_window(related_id, &instance.name)?;
+                let mapped = x.is_mapped(related_id)?;
+                x.unmap_window(related_id)?;
+                Ok(mapped)
+            };
+            match hide() {
+                Ok(mapped) => {
+                    hidden.insert(related_id);
+                    if mapped {
+                        unmapped.insert(related_id);
+                    }
+                }
+                Err(Error::WindowGone(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if x.is_mapped(window_id)? {
+            unmapped.insert(window_id);
+        }
+        x.unmap_window(window_id)?;
+        Ok((hidden, unmapped))
+    })?;
+    let mut write_ctx = ctx.write().unwrap();
+    write_ctx.expected_unmaps.extend(unmapped);
+    write_ctx.windows = windows;
+    Ok(())
+}
+
+/// Show the other windows belonging to the instance, forgetting any that have gone away.
+fn show_related(display: &x11::DisplayHandle, ctx: &Arc<RwLock<Context>>) -> Result<(), Error> {
+    let related = ctx.read().unwrap().windows.clone();
+    let shown = display.with(|x| {
+        let mut shown = BTreeSet::new();
+        for related_id in &related {
+            match x.show_related_window(*related_id) {
+                Ok(()) => {
+                    shown.insert(*related_id);
+                }
+                Err(Error::WindowGone(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(shown)
+    })?;
+    ctx.write().unwrap().windows = shown;
+    Ok(())
+}
+
+/// Hide the instance after another window became active, unless that window belongs to the
+/// instance too (e.g., it's a newly-opened dialog).
+fn hide_unfocused(
+    display: &x11::DisplayHandle,
+    active_window: u32,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+    events: &ipc::Subscribers,
+) -> Result<(), Error> {
+    let (window_id, matcher, owned) = {
+        let read_ctx = ctx.read().unwrap();
+        (
+            read_ctx.window_id,
+            read_ctx.matcher.clone(),
+            read_ctx.owned_windows(),
+        )
+    };
+    let Some(window_id) = window_id else {
+        return Ok(());
+    };
+    if !display.with(|x| x.window_is_mapped(window_id))? {
+        return Ok(());
+    }
+    match display.with(|x| x.belongs_to(active_window, &instance.name, &matcher, &owned)) {
+        Ok(true) => {
+            display.with(|x| x.watch_window(active_window))?;
+            debug!(
+                "[{}] Window {} belongs to the instance",
+                instance.name, active_window
+            );
+            ctx.write().unwrap().windows.insert(active_window);
+            return Ok(());
+        }
+        Ok(false) | Err(Error::WindowGone(_)) => {}
+        Err(err) => return Err(err),
+    }
+    hide_windows(display, window_id, instance, ctx)?;
+    events.publish(&instance.name, ipc::EventKind::Hidden { window_id });
+    Ok(())
+}
+
+/// Forget a window that was unmapped or destroyed by something other than qurop. If it was the
+/// main window, another is searched for on the next open.
+fn forget_window(
+    window_id: u32,
+    destroyed: bool,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+    events: &ipc::Subscribers,
+) {
+    let mut write_ctx = ctx.write().unwrap();
+    if write_ctx.expected_unmaps.remove(&window_id) && !destroyed {
+        trace!(
+            "[{}] Window {} was hidden by qurop",
+            instance.name,
+            window_id
+        );
+        return;
+    }
+    let how = if destroyed { "destroyed" } else { "unmapped" };
+    if write_ctx.window_id == Some(window_id) {
+        info!(
+            "[{}] Window {} was {} elsewhere, will search again on the next open",
+            instance.name, window_id, how
+        );
+        write_ctx.set_window(None);
+        events.publish(&instance.name, ipc::EventKind::Lost { window_id });
+    } else if write_ctx.windows.remove(&window_id) {
+        debug!("[{}] Window {} was {}", instance.name, window_id, how);
+    }
+}
+
+/// Show the program's window, starting the program or finding the window again if needed.
+fn open(
+    display: &x11::DisplayHandle,
+    program: &mut Option<process::Child>,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+    events: &ipc::Subscribers,
+) -> Result<ipc::Response, Error> {
+    let (adopted, exit_status) = {
+        let read_ctx = ctx.read().unwrap();
+        (read_ctx.adopted, read_ctx.exit_status)
+    };
+    match program {
+        None if !adopted => match exit_status {
+            Some(status) if !instance.restart.restarts_on_show(status) => {
+                return Ok(ipc::Response::error(format!(
+                    "'{}' has exited ({}) and its restart policy leaves it stopped. \
+                     Use `qurop kill {}` to reset it.",
+                    instance.name, status, instance.name
+                )));
+            }
+            Some(status) => {
+                info!(
+                    "[{}] Program has exited ({}). Restarting.",
+                    instance.name, status
+                );
+                let child = spawn_program(instance, ctx)?;
+                events.publish(
+                    &instance.name,
+                    ipc::EventKind::Restarted { pid: child.id() },
+                );
+                *program = Some(child);
+                let window_id = discover_window(display, instance, ctx)?;
+                events.publish(&instance.name, ipc::EventKind::Rediscovered { window_id });
+                events.publish(&instance.name, ipc::EventKind::Shown { window_id });
+            }
+            None => {
+                if instance.adopt != AdoptPolicy::Never
+                    && adopt(display, instance, ctx, events)?.is_some()
+                {
+                    return open(display, program, instance, ctx, events);
+                }
+                if instance.adopt == AdoptPolicy::AdoptOnly {
+                    return Ok(ipc::Response::error(format!(
+                        "No window found to adopt for '{}'",
+                        instance.name
+                    )));
+                }
+                *program = Some(spawn_program(instance, ctx)?);
+                let window_id = discover_window(display, instance, ctx)?;
+                events.publish(&instance.name, ipc::EventKind::Shown { window_id });
+            }
+        },
+        _ => {
+            let window_id = ctx.read().unwrap().window_id;
+            let shown = window_id.map(|window_id| {
+                display.with(|x| {
+                    x.map_window(window_id)?;
+                    x.position_window(window_id, instance)
+                })
+            });
+            match shown {
+                Some(Ok(())) => show_related(display, ctx)?,
+                Some(Err(Error::WindowGone(_))) | None if adopted => {
+                    info!("[{}] Adopted window is gone", instance.name);
+                    let mut write_ctx = ctx.write().unwrap();
+                    write_ctx.set_window(None);
+                    write_ctx.pid = None;
+                    write_ctx.adopted = false;
+                    drop(write_ctx);
+                    return open(display, program, instance, ctx, events);
+                }
+                Some(Err(Error::WindowGone(_))) | None => {
+                    warn!("[{}] Window missing, searching for it again", instance.name);
+                    let window_id = discover_window(display, instance, ctx)?;
+                    events.publish(&instance.name, ipc::EventKind::Rediscovered { window_id });
+                }
+                Some(Err(err)) => return Err(err),
+            }
+            if let Some(window_id) = ctx.read().unwrap().window_id {
+                events.publish(&instance.name, ipc::EventKind::Shown { window_id });
+            }
+        }
+    }
+    Ok(ipc::Response::ok(Some(true)))
+}
+
+/// Hide the program's window.
+fn hide(
+    display: &x11::DisplayHandle,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+    events: &ipc::Subscribers,
+) -> Result<ipc::Response, Error> {
+    let window_id = ctx.read().unwrap().window_id;
+    match window_id {
+        Some(window_id) => match hide_windows(display, window_id, instance, ctx) {
+            Ok(()) => events.publish(&instance.name, ipc::EventKind::Hidden { window_id }),
+            Err(Error::WindowGone(_)) => {
+                warn!("[{}] Window {} no longer exists", instance.name, window_id);
+                ctx.write().unwrap().set_window(None);
+            }
+            Err(err) => return Err(err),
+        },
+        None => {
+            let matcher = ctx.read().unwrap().matcher.clone();
+            display.with(|x| x.unmap_qurop_window(&instance.name, &matcher))?;
+        }
+    }
+    Ok(ipc::Response::ok(Some(false)))
+}
+
+/// Report the state of the instance.
+fn status(
+    display: &x11::DisplayHandle,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+) -> Result<ipc::Response, Error> {
+    let read_ctx = ctx.read().unwrap();
+    let exit_status = read_ctx.exit_status;
+    let (mapped, active) = match read_ctx.window_id {
+        Some(window_id) => match display.with(|x| {
+            Ok((
+                x.window_is_mapped(window_id)?,
+                x.get_active_window()?
+                    .is_some_and(|active_window| read_ctx.owns(active_window)),
+            ))
+        }) {
+            Ok(state) => state,
+            Err(Error::WindowGone(_)) => (false, false),
+            Err(err) => return Err(err),
+        },
+        None => (false, false),
+    };
+    Ok(ipc::Response::status(ipc::InstanceStatus {
+        name: instance.name.clone(),
+        matcher: read_ctx.matcher.clone(),
+        window_id: read_ctx.window_id,
+        pid: read_ctx.pid,
+        exited: exit_status.is_some(),
+        exit_code: exit_status.and_then(|status| status.code()),
+        mapped,
+        active,
+        uptime_secs: read_ctx.started.elapsed().as_secs(),
+    }))
+}
+
+/// Pick the instance back up after reconnecting to the X server, according to its policy.
+fn reattach(
+    display: &x11::DisplayHandle,
+    program: &mut Option<process::Child>,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+    events: &ipc::Subscribers,
+) -> Result<(), Error> {
+    let running = program.is_some();
+    let mut write_ctx = ctx.write().unwrap();
+    write_ctx.set_window(None);
+    if !running && write_ctx.exit_status.is_none() {
+        debug!("[{}] Not started, nothing to reattach", instance.name);
+        return Ok(());
+    }
+    drop(write_ctx);
+    match instance.on_reconnect {
+        ReconnectPolicy::Rediscover if running => {
+            let matcher = ctx.read().unwrap().matcher.clone();
+            let found = display.with(|x| {
+                let window_id = x.get_qurop_window_id(&instance.name, &matcher)?;
+                if let Some(window_id) = window_id {
+                    x.tag_window(window_id, &instance.name)?;
+                }
+                Ok(window_id)
+            })?;
+            match found {
+                Some(window_id) => {
+                    info!("[{}] Rediscovered window {}", instance.name, window_id);
+                    ctx.write().unwrap().set_window(Some(window_id));
+                    events.publish(&instance.name, ipc::EventKind::Rediscovered { window_id });
+                }
+                None => info!(
+                    "[{}] Window not found, will search again on the next open",
+                    instance.name
+                ),
+            }
+        }
+        ReconnectPolicy::Relaunch if running => {
+            kill_program(program, instance, ctx, events)?;
+            restart(display, program, instance, ctx, events)?;
+            info!("[{}] Relaunched after reconnecting", instance.name);
+        }
+        ReconnectPolicy::Rediscover | ReconnectPolicy::Relaunch => {
+            info!(
+                "[{}] Not running, leaving it to its restart policy",
+                instance.name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Stop managing the instance, either killing the program or leaving its window visible.
+fn release(
+    display: &x11::DisplayHandle,
+    kill: bool,
+    program: &mut Option<process::Child>,
+    instance: &Instance,
+    ctx: &Arc<RwLock<Context>>,
+    events: &ipc::Subscribers,
+) -> Result<ipc::Response, Error> {
+    if kill && program.is_some() {
+        return kill_program(program, instance, ctx, events);
+    }
+    let window_id = ctx.read().unwrap().window_id;
+    if let Some(window_id) = window_id {
+        let related = ctx.read().unwrap().windows.clone();
+        display.with(|x| {
+            x.restore_window(window_id)?;
+            for related_id in &related {
+                match x.restore_window(*related_id) {
+                    Ok(()) | Err(Error::WindowGone(_)) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(())
+        })?;
+        events.publish(&instance.name, ipc::EventKind::Shown { window_id });
+    }
+    Ok(ipc::Response::ok(Some(window_id.is_some())))
+}
+
+pub(crate) fn program_thread(
+    rx: mpsc::Receiver<Message>,
+    tx: mpsc::Sender<Message>,
+    display: &x11::DisplayHandle,
+    instance: Instance,
+    ctx: &Arc<RwLock<Context>>,
+    events: &ipc::Subscribers,
+) {
+    // The program is deliberately left running if the daemon goes away.
+    let mut program: Option<process::Child> = None;
+    // Captured windows are handed over already adopted.
+    if instance.adopt != AdoptPolicy::Never && !ctx.read().unwrap().adopted {
+        if let Err(err) = adopt(display, &instance, ctx, events) {
+            warn!("[{}] Couldn't adopt a window: {}", instance.name, err);
+        }
+    }
+    let mut restarts = Restarts::default();
+    let mut watched = None;
+    loop {
+        // Watch each newly-started program, so its exit is noticed right away.
+        if let Some(pid) = program
+            .as_ref()
+            .map(process::Child::id)
+            .filter(|pid| watched != Some(*pid))
+        {
+            watch_exit(pid, tx.clone());
+            watched = Some(pid);
+            restarts.started = Some(Instant::now());
+        }
+        let msg = match restarts.due {
+            Some(due) => match rx.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                Ok(msg) => msg,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    restarts.due = None;
+                    // It may have been started again by being shown in the meantime.
+                    if program.is_none() {
+                        if let Err(err) = restart(display, &mut program, &instance, ctx, events) {
+                            warn!("[{}] Couldn't restart: {}", instance.name, err);
+                        }
+                    }
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(msg) => msg,
+                Err(_) => break,
+            },
+        };
+        match reap(&mut program, &instance, ctx, events) {
+            Ok(Some(status)) if instance.restart.restarts_on_exit(status) => {
+                restarts.schedule(&instance.name);
+            }
+            Ok(_) => {}
+            Err(err) => warn!("[{}] Couldn't check on the program: {}", instance.name, err),
+        }
+        let (action, response_tx) = match msg {
+            Message::Exited => continue,
+            Message::FocusLost(active_window) => {
+                if let Err(err) = hide_unfocused(display, active_window, &instance, ctx, events) {
+                    warn!("[{}] Couldn't hide window: {}", instance.name, err);
+                }
+                continue;
+            }
+            Message::Reconnected => {
+                if let Err(err) = reattach(display, &mut program, &instance, ctx, events) {
+                    warn!("[{}] Couldn't reattach: {}", instance.name, err);
+                }
+                continue;
+            }
+            Message::Unmapped(window_id) => {
+                forget_window(window_id, false, &instance, ctx, events);
+                continue;
+            }
+            Message::Destroyed(window_id) => {
+                forget_window(window_id, true, &instance, ctx, events);
+                continue;
+            }
+            Message::Request(action, response_tx) => (action, response_tx),
+        };
+        let action = if action == ipc::Action::Toggle {
+            // Any of the instance's windows (e.g., one of its dialogs) being active counts.
+            match display.with(|x| x.get_active_window()) {
+                Ok(Some(active_window)) if ctx.read().unwrap().owns(active_window) => {
+                    ipc::Action::Hide
+                }
+                Ok(_) => ipc::Action::Open,
+                Err(err) => {
+                    let _ = response_tx.send(ipc::Response::error(err.to_string()));
+                    continue;
+                }
+            }
+        } else {
+            action
+        };
+        debug!("[{}] Taking action: '{:?}'", instance.name, action);
+        let result = match action {
+            ipc::Action::Open => open(display, &mut program, &instance, ctx, events),
+            ipc::Action::Kill => {
+                restarts.due = None;
+                kill_program(&mut program, &instance, ctx, events)
+            }
+            ipc::Action::Hide => hide(display, &instance, ctx, events),
+            ipc::Action::Status => status(display, &instance, ctx),
+            ipc::Action::Shutdown { kill } => {
+                let result = release(display, kill, &mut program, &instance, ctx, events);
+                info!("[{}] No longer managing the instance", instance.name);
+                let _ = response_tx
+                    .send(result.unwrap_or_else(|err| ipc::Response::error(err.to_string())));
+                break;
+            }
+            ipc::Action::Toggle
+            | ipc::Action::Subscribe
+            | ipc::Action::List
+            | ipc::Action::Ping
+            | ipc::Action::Capture
+            | ipc::Action::Release => {
+                info!("[{}] Unhandled: '{:?}'", instance.name, action);
+                Ok(ipc::Response::error(format!(
+                    "Unhandled action: {action:?}"
+                )))
+            }
+        };
+        let response = result.unwrap_or_else(|err| {
+            warn!("[{}] Couldn't {:?}: {}", instance.name, action, err);
+            ipc::Response::error(err.to_string())
+        });
+        let _ = response_tx.send(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarts_back_off_then_give_up() {
+        let mut restarts = Restarts::default();
+        let delays: Vec<_> = (0..=RESTART_LIMIT)
+            .map(|_| restarts.schedule("test").map(|delay| delay.as_secs()))
+            .collect();
+        assert_eq!(delays, [Some(1), Some(2), Some(4), Some(8), Some(16), None]);
+    }
+
+    #[test]
+    fn restarts_reset_once_the_program_is_stable() {
+        let mut restarts = Restarts::default();
+        while restarts.schedule("test").is_some() {}
+        restarts.started = Instant::now().checked_sub(RESTART_STABLE_AFTER);
+        assert_eq!(restarts.schedule("test"), Some(RESTART_BACKOFF_MIN));
+    }
+}
@@ -0,0 +1,415 @@
+use std::{
+    fmt,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{debug, trace, warn};
+
+use crate::{errors::Error, structs::WindowMatcher, utils::format_duration};
+
+/// The version of the socket protocol. Bump this whenever a message changes shape.
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+
+/// Actions a client can request of the daemon or one of its instances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Action {
+    Open,
+    Toggle,
+    Hide,
+    Kill,
+    Status,
+    Subscribe,
+    List,
+    Ping,
+    /// Stop managing the instance (or, daemon-wide, shut down), optionally killing the program.
+    Shutdown {
+        kill: bool,
+    },
+    /// Manage the active window as a new instance.
+    Capture,
+    /// Stop managing a captured instance, handing its window back.
+    Release,
+}
+
+/// A single request sent from a client to the server.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Request {
+    pub(crate) version: u32,
+    /// The instance the request targets. Daemon-wide requests leave this empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) instance: Option<String>,
+    pub(crate) action: Action,
+}
+
+impl Request {
+    pub(crate) fn new(action: Action, instance: Option<&str>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            instance: instance.map(String::from),
+            action,
+        }
+    }
+}
+
+/// The server's reply to a single request.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Response {
+    pub(crate) version: u32,
+    pub(crate) success: bool,
+    /// Whether the window is visible once the request has been handled, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) visible: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    /// What happened while handling the request, for the client to pass on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) status: Option<InstanceStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) instances: Option<Vec<InstanceStatus>>,
+}
+
+/// A snapshot of the server's view of an instance.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct InstanceStatus {
+    pub(crate) name: String,
+    pub(crate) matcher: WindowMatcher,
+    pub(crate) window_id: Option<u32>,
+    pub(crate) pid: Option<u32>,
+    pub(crate) exited: bool,
+    pub(crate) exit_code: Option<i32>,
+    pub(crate) mapped: bool,
+    pub(crate) active: bool,
+    pub(crate) uptime_secs: u64,
+}
+
+impl fmt::Display for InstanceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn yes_no(value: bool) -> &'static str {
+            if value {
+                "yes"
+            } else {
+                "no"
+            }
+        }
+        let window = self
+            .window_id
+            .map(|id| format!("{id:#x}"))
+            .unwrap_or_else(|| "-".into());
+        let pid = self
+            .pid
+            .map(|pid| pid.to_string())
+            .unwrap_or_else(|| "-".into());
+        let exited = match (self.exited, self.exit_code) {
+            (true, Some(code)) => format!("yes ({code})"),
+            (exited, _) => yes_no(exited).into(),
+        };
+        writeln!(f, "{:<10}{}", "Instance", self.name)?;
+        writeln!(f, "{:<10}{}", "Matcher", self.matcher)?;
+        writeln!(f, "{:<10}{}", "Window", window)?;
+        writeln!(f, "{:<10}{}", "PID", pid)?;
+        writeln!(f, "{:<10}{}", "Exited", exited)?;
+        writeln!(f, "{:<10}{}", "Mapped", yes_no(self.mapped))?;
+        writeln!(f, "{:<10}{}", "Active", yes_no(self.active))?;
+        write!(
+            f,
+            "{:<10}{}",
+            "Uptime",
+            format_duration(Duration::from_secs(self.uptime_secs))
+        )
+    }
+}
+
+impl Response {
+    pub(crate) fn ok(visible: Option<bool>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            success: true,
+            visible,
+            error: None,
+            message: None,
+            status: None,
+            instances: None,
+        }
+    }
+
+    pub(crate) fn message(visible: Option<bool>, message: impl Into<String>) -> Self {
+        Self {
+            message: Some(message.into()),
+            ..Self::ok(visible)
+        }
+    }
+
+    pub(crate) fn status(status: InstanceStatus) -> Self {
+        Self {
+            visible: Some(status.mapped),
+            status: Some(status),
+            ..Self::ok(None)
+        }
+    }
+
+    pub(crate) fn instances(instances: Vec<InstanceStatus>) -> Self {
+        Self {
+            instances: Some(instances),
+            ..Self::ok(None)
+        }
+    }
+
+    pub(crate) fn error(message: impl Into<String>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            success: false,
+            visible: None,
+            error: Some(message.into()),
+            message: None,
+            status: None,
+            instances: None,
+        }
+    }
+}
+
+/// Something that happened to an instance, streamed to subscribers.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Event {
+    pub(crate) version: u32,
+    pub(crate) instance: String,
+    #[serde(flatten)]
+    pub(crate) kind: EventKind,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub(crate) enum EventKind {
+    /// The window was mapped.
+    Shown { window_id: u32 },
+    /// The window was unmapped, either by request or because it lost focus.
+    Hidden { window_id: u32 },
+    /// The program exited.
+    Exited { code: Option<i32> },
+    /// The program was started again after exiting.
+    Restarted { pid: u32 },
+    /// A new window was found for the instance.
+    Rediscovered { window_id: u32 },
+    /// The window was closed or hidden by something other than qurop. A window is searched for
+    /// again on the next open.
+    Lost { window_id: u32 },
+}
+
+/// A client subscribed to events, optionally only those of a single instance.
+#[derive(Debug)]
+struct Subscriber {
+    instance: Option<String>,
+    stream: UnixStream,
+}
+
+/// The set of clients subscribed to instance events.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Subscribers(Arc<Mutex<Vec<Subscriber>>>);
+
+impl Subscribers {
+    /// Add a subscriber. Slow subscribers are dropped rather than stalling the server.
+    pub(crate) fn add(&self, stream: UnixStream, instance: Option<String>) -> Result<(), Error> {
+        stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+        self.0.lock().unwrap().push(Subscriber { instance, stream });
+        Ok(())
+    }
+
+    /// Send an event to every subscriber, dropping any that have gone away.
+    pub(crate) fn publish(&self, instance: &str, kind: EventKind) {
+        let event = Event {
+            version: PROTOCOL_VERSION,
+            instance: instance.into(),
+            kind,
+        };
+        debug!(?event, "publishing event");
+        self.0.lock().unwrap().retain(|subscriber| {
+            if subscriber
+                .instance
+                .as_ref()
+                .is_some_and(|name| *name != event.instance)
+            {
+                return true;
+            }
+            match send(&subscriber.stream, &event) {
+                Ok(()) => true,
+                Err(err) => {
+                    debug!("Dropping subscriber: {}", err);
+                    false
+                }
+            }
+        });
+    }
+}
+
+/// Write a message to the stream as a single line of JSON.
+pub(crate) fn send<T: Serialize>(mut stream: &UnixStream, message: &T) -> Result<(), Error> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Read the next line-delimited JSON message from the stream. Returns `None` on EOF.
+pub(crate) fn receive<T: DeserializeOwned>(
+    reader: &mut BufReader<UnixStream>,
+) -> Result<Option<T>, Error> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    trace!("Received: {}", line.trim_end());
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+/// The version carried by every message, read on its own so that a request from a newer client is
+/// reported as a version mismatch rather than as malformed.
+#[derive(Deserialize)]
+struct Versioned {
+    version: u32,
+}
+
+/// Read the next request from the stream, checking its version before the rest of it. Returns
+/// `None` on EOF.
+pub(crate) fn receive_request(
+    reader: &mut BufReader<UnixStream>,
+) -> Result<Option<Request>, Error> {
+    let Some(message) = receive::<serde_json::Value>(reader)? else {
+        return Ok(None);
+    };
+    let Versioned { version } = Versioned::deserialize(&message)?;
+    if version != PROTOCOL_VERSION {
+        return Err(Error::UnsupportedVersion {
+            found: version,
+            expected: PROTOCOL_VERSION,
+        });
+    }
+    Ok(Some(serde_json::from_value(message)?))
+}
+
+/// Send a request over the stream and wait for the server's response.
+pub(crate) fn request(stream: UnixStream, request: &Request) -> Result<Response, Error> {
+    send(&stream, request)?;
+    let mut reader = BufReader::new(stream);
+    let response: Response = receive(&mut reader)?
+        .unwrap_or_else(|| Response::error("The server closed the connection without responding"));
+    if response.version != PROTOCOL_VERSION {
+        warn!(
+            "Server speaks protocol version {} (expected {})",
+            response.version, PROTOCOL_VERSION
+        );
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// Check that the message survives a round trip through JSON unchanged.
+    fn round_trip<T: Serialize + DeserializeOwned>(message: &T) -> Value {
+        let encoded = serde_json::to_string(message).unwrap();
+        let decoded: T = serde_json::from_str(&encoded).unwrap();
+        let value = serde_json::to_value(decoded).unwrap();
+        assert_eq!(value, serde_json::from_str::<Value>(&encoded).unwrap());
+        value
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        assert_eq!(
+            round_trip(&Request::new(Action::Toggle, Some("term"))),
+            json!({"version": PROTOCOL_VERSION, "instance": "term", "action": "toggle"})
+        );
+        assert_eq!(
+            round_trip(&Request::new(Action::Shutdown { kill: true }, None)),
+            json!({"version": PROTOCOL_VERSION, "action": {"shutdown": {"kill": true}}})
+        );
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let status = InstanceStatus {
+            name: "term".into(),
+            matcher: WindowMatcher::WmClass("Alacritty".into()),
+            window_id: Some(0x1a00002),
+            pid: None,
+            exited: true,
+            exit_code: Some(1),
+            mapped: false,
+            active: false,
+            uptime_secs: 42,
+        };
+        assert_eq!(
+            round_trip(&Response::ok(Some(true))),
+            json!({"version": PROTOCOL_VERSION, "success": true, "visible": true})
+        );
+        assert_eq!(
+            round_trip(&Response::error("nope")),
+            json!({"version": PROTOCOL_VERSION, "success": false, "error": "nope"})
+        );
+        round_trip(&Response::message(None, "Released"));
+        round_trip(&Response::status(status.clone()));
+        round_trip(&Response::instances(vec![status]));
+    }
+
+    #[test]
+    fn events_round_trip() {
+        let event = |kind| Event {
+            version: PROTOCOL_VERSION,
+            instance: "term".into(),
+            kind,
+        };
+        assert_eq!(
+            round_trip(&event(EventKind::Shown { window_id: 7 })),
+            json!({"version": PROTOCOL_VERSION, "instance": "term", "event": "shown", "window_id": 7})
+        );
+        assert_eq!(
+            round_trip(&event(EventKind::Exited { code: None })),
+            json!({"version": PROTOCOL_VERSION, "instance": "term", "event": "exited", "code": null})
+        );
+        round_trip(&event(EventKind::Hidden { window_id: 7 }));
+        round_trip(&event(EventKind::Restarted { pid: 4123 }));
+        round_trip(&event(EventKind::Rediscovered { window_id: 8 }));
+        round_trip(&event(EventKind::Lost { window_id: 8 }));
+    }
+
+    /// Send a raw line to `receive_request`.
+    fn receive_line(line: &str) -> Result<Option<Request>, Error> {
+        let (client, server) = UnixStream::pair().unwrap();
+        (&client).write_all(line.as_bytes()).unwrap();
+        drop(client);
+        receive_request(&mut BufReader::new(server))
+    }
+
+    #[test]
+    fn requests_are_received() {
+        let request = receive_line(&format!(
+            "{{\"version\":{PROTOCOL_VERSION},\"action\":\"ping\"}}\n"
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.action, Action::Ping);
+        assert!(receive_line("").unwrap().is_none());
+    }
+
+    #[test]
+    fn other_versions_are_reported_before_the_action() {
+        let err = receive_line("{\"version\":99,\"action\":\"teleport\"}\n").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::UnsupportedVersion {
+                found: 99,
+                expected: PROTOCOL_VERSION
+            }
+        ));
+    }
+}
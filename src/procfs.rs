@@ -0,0 +1,137 @@
+use std::{cell::OnceCell, collections::BTreeMap, fs};
+
+use tracing::trace;
+
+/// Find a field (e.g., `PPid`) in the contents of a `/proc/<pid>/status` file.
+fn parse_status_field<'a>(status: &'a str, field: &str) -> Option<&'a str> {
+    status.lines().find_map(|line| {
+        line.strip_prefix(field)
+            .and_then(|rest| rest.strip_prefix(':'))
+            .map(str::trim)
+    })
+}
+
+/// Read a field (e.g., `PPid`) from `/proc/<pid>/status`.
+fn status_field(pid: u32, field: &str) -> Option<String> {
+    let status = fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    parse_status_field(&status, field).map(String::from)
+}
+
+/// Get the PID of the process's parent, if the process still exists.
+pub(crate) fn parent_pid(pid: u32) -> Option<u32> {
+    status_field(pid, "PPid")?.parse().ok()
+}
+
+/// Determine if the process is the ancestor, or one of its descendants. Processes that have been
+/// reparented (e.g., by daemonizing) are no longer considered descendants.
+pub(crate) fn is_descendant(pid: u32, ancestor: u32) -> bool {
+    let mut current = pid;
+    // Bounded, in case of a cycle from PIDs being reused mid-walk.
+    for _ in 0..64 {
+        if current == ancestor {
+            return true;
+        }
+        match parent_pid(current) {
+            Some(parent) if parent > 1 => current = parent,
+            _ => break,
+        }
+    }
+    trace!("PID {} is not a descendant of {}", pid, ancestor);
+    false
+}
+
+/// Parse an `NSpid` value into the process's PID in each PID namespace it belongs to, from the
+/// host's outward-in.
+fn parse_namespace_pids(value: &str) -> Option<Vec<u32>> {
+    value
+        .split_whitespace()
+        .map(|ns_pid| ns_pid.parse().ok())
+        .collect()
+}
+
+/// The host PIDs of sandboxed processes, by their PID inside their own PID namespace. Read from
+/// `/proc` on first use, so that a search reads every process's status at most once.
+#[derive(Default)]
+pub(crate) struct SandboxedPids(OnceCell<BTreeMap<u32, Vec<u32>>>);
+
+impl SandboxedPids {
+    /// Find the host PIDs of sandboxed processes known as `ns_pid` inside their own PID namespace.
+    pub(crate) fn host_pids(&self, ns_pid: u32) -> &[u32] {
+        self.0
+            .get_or_init(read_sandboxed_pids)
+            .get(&ns_pid)
+            .map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Read the host PIDs of every sandboxed process, by their PID inside their own PID namespace.
+fn read_sandboxed_pids() -> BTreeMap<u32, Vec<u32>> {
+    let mut sandboxed: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return sandboxed;
+    };
+    for pid in entries.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok()) {
+        let Some(ns_pids) = status_field(pid, "NSpid")
+            .as_deref()
+            .and_then(parse_namespace_pids)
+        else {
+            continue;
+        };
+        if let [_, .., ns_pid] = ns_pids[..] {
+            sandboxed.entry(ns_pid).or_default().push(pid);
+        }
+    }
+    sandboxed
+}
+
+/// Get the ID of the flatpak app the process belongs to, from the sandbox's `.flatpak-info`.
+pub(crate) fn flatpak_app_id(pid: u32) -> Option<String> {
+    let info = fs::read_to_string(format!("/proc/{pid}/root/.flatpak-info")).ok()?;
+    parse_flatpak_app_id(&info)
+}
+
+/// Find the app ID in the contents of a `.flatpak-info` file.
+fn parse_flatpak_app_id(info: &str) -> Option<String> {
+    let mut in_application = false;
+    for line in info.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_application = line == "[Application]";
+        } else if in_application {
+            if let Some(name) = line.strip_prefix("name=") {
+                return Some(name.to_string());
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = "Name:\tobsidian\nPPid:\t4123\nNSpid:\t4170\t2\t1\n";
+
+    #[test]
+    fn status_fields_are_found_by_name() {
+        assert_eq!(parse_status_field(STATUS, "PPid"), Some("4123"));
+        assert_eq!(parse_status_field(STATUS, "Pid"), None);
+    }
+
+    #[test]
+    fn namespace_pids_run_from_the_host_inward() {
+        let nspid = parse_status_field(STATUS, "NSpid").unwrap();
+        assert_eq!(parse_namespace_pids(nspid), Some(vec![4170, 2, 1]));
+        assert_eq!(parse_namespace_pids("812"), Some(vec![812]));
+        assert_eq!(parse_namespace_pids("812 x"), None);
+    }
+
+    #[test]
+    fn flatpak_app_id_is_read_from_the_application_section() {
+        let info = "[Instance]\nname=ignored\n\n[Application]\nname=md.obsidian.Obsidian\n";
+        assert_eq!(
+            parse_flatpak_app_id(info).as_deref(),
+            Some("md.obsidian.Obsidian")
+        );
+        assert_eq!(parse_flatpak_app_id("[Runtime]\nname=org.foo.Sdk\n"), None);
+    }
+}
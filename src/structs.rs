@@ -1,12 +1,160 @@
-use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::PathBuf,
+    process::ExitStatus,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Debug, Clone)]
 pub(crate) struct Instance {
     pub(crate) name: String,
-    pub(crate) command: String,
+    pub(crate) command: Command,
+    pub(crate) env: BTreeMap<String, String>,
+    pub(crate) unset_env: Vec<String>,
+    pub(crate) cwd: Option<PathBuf>,
     pub(crate) matcher: WindowMatcher,
     pub(crate) window_delay: Option<u64>,
-    pub(crate) geometry: WindowGeometry,
+    pub(crate) geometry: Geometry,
+    pub(crate) on_reconnect: ReconnectPolicy,
+    pub(crate) adopt: AdoptPolicy,
+    pub(crate) restart: RestartPolicy,
+    /// The signal sent to the program's process group to stop it.
+    pub(crate) kill_signal: Signal,
+    /// How long to wait for the program to stop before killing it outright.
+    pub(crate) kill_timeout: Duration,
+}
+
+/// The signals a program can be stopped with, by name.
+const SIGNALS: [(&str, libc::c_int); 7] = [
+    ("SIGHUP", libc::SIGHUP),
+    ("SIGINT", libc::SIGINT),
+    ("SIGQUIT", libc::SIGQUIT),
+    ("SIGKILL", libc::SIGKILL),
+    ("SIGUSR1", libc::SIGUSR1),
+    ("SIGUSR2", libc::SIGUSR2),
+    ("SIGTERM", libc::SIGTERM),
+];
+
+/// A signal used to stop a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Signal(libc::c_int);
+
+impl Signal {
+    pub(crate) const TERM: Signal = Signal(libc::SIGTERM);
+    pub(crate) const KILL: Signal = Signal(libc::SIGKILL);
+
+    pub(crate) fn number(self) -> libc::c_int {
+        self.0
+    }
+}
+
+impl Default for Signal {
+    fn default() -> Self {
+        Signal::TERM
+    }
+}
+
+impl FromStr for Signal {
+    type Err = String;
+
+    /// Parse a signal name, with or without its `SIG` prefix (e.g., `SIGTERM` or `term`).
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let name = name.to_uppercase();
+        let name = name.strip_prefix("SIG").unwrap_or(&name);
+        SIGNALS
+            .iter()
+            .find(|(signal_name, _)| signal_name[3..] == *name)
+            .map(|(_, number)| Signal(*number))
+            .ok_or_else(|| {
+                let names: Vec<_> = SIGNALS
+                    .iter()
+                    .map(|(signal_name, _)| *signal_name)
+                    .collect();
+                format!("expected one of {}", names.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match SIGNALS.iter().find(|(_, number)| *number == self.0) {
+            Some((name, _)) => write!(f, "{name}"),
+            None => write!(f, "signal {}", self.0),
+        }
+    }
+}
+
+/// How the program is started.
+#[derive(Debug, Clone)]
+pub(crate) enum Command {
+    /// A command line, run by the shell.
+    Shell(String),
+    /// A program, run directly with the given arguments.
+    Exec { program: String, args: Vec<String> },
+}
+
+/// When to start the program again after it exits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RestartPolicy {
+    /// Leave the program stopped.
+    Never,
+    /// Start the program again the next time the instance is shown.
+    #[default]
+    OnShow,
+    /// Start the program again right away.
+    Always,
+    /// Start the program again right away if it failed, otherwise leave it stopped.
+    OnFailure,
+}
+
+impl RestartPolicy {
+    /// Determine if showing the instance should start the program again after it exited.
+    pub(crate) fn restarts_on_show(self, status: ExitStatus) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnShow | RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => !status.success(),
+        }
+    }
+
+    /// Determine if the program should be started again as soon as it exits.
+    pub(crate) fn restarts_on_exit(self, status: ExitStatus) -> bool {
+        match self {
+            RestartPolicy::Never | RestartPolicy::OnShow => false,
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => !status.success(),
+        }
+    }
+}
+
+/// Whether to take over an already-running window rather than launching the program.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AdoptPolicy {
+    /// Always launch the program.
+    #[default]
+    Never,
+    /// Take over a matching window if there is one, otherwise launch the program.
+    AdoptOrSpawn,
+    /// Only ever take over a matching window.
+    AdoptOnly,
+}
+
+/// What to do with an instance after the daemon reconnects to a restarted X server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ReconnectPolicy {
+    /// Look for the program's window again, leaving the program be.
+    #[default]
+    Rediscover,
+    /// Restart the program, as its window likely went away with the old server.
+    Relaunch,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -16,43 +164,317 @@ pub(crate) struct WindowGeometry {
 }
 
 impl WindowGeometry {
-    /// Get the dimensions of the instance based on the screen dimensions.
-    pub(crate) fn get_dimensions(&self, screen_width: u16, screen_height: u16) -> (u32, u32) {
-        let width: u32 = if self.width.ends_with('%') {
-            let width_pct = self
+    /// Parse the configured width and height.
+    pub(crate) fn parse(&self) -> Result<Geometry, String> {
+        Ok(Geometry {
+            width: self
                 .width
-                .strip_suffix('%')
-                .unwrap()
-                .parse::<f64>()
-                .expect("invalid width")
-                / 100.0;
-            (screen_width as f64 * width_pct) as u32
-        } else {
-            self.width.parse().expect("invalid width")
-        };
-        let height: u32 = if self.height.ends_with('%') {
-            let height_pct = self
+                .parse()
+                .map_err(|err| format!("invalid width: {err}"))?,
+            height: self
                 .height
-                .strip_suffix('%')
-                .unwrap()
+                .parse()
+                .map_err(|err| format!("invalid height: {err}"))?,
+        })
+    }
+}
+
+/// A window dimension, either in pixels or as a percentage of the screen's.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Length {
+    Pixels(u32),
+    Percent(f64),
+}
+
+impl Length {
+    /// Get the length in pixels, kept within the screen.
+    fn to_pixels(self, screen_length: u16) -> u32 {
+        let pixels = match self {
+            Length::Pixels(pixels) => pixels,
+            Length::Percent(percent) => (screen_length as f64 * percent / 100.0) as u32,
+        };
+        pixels.clamp(1, u32::from(screen_length).max(1))
+    }
+}
+
+impl FromStr for Length {
+    type Err = String;
+
+    /// Parse a number of pixels (e.g., `"800"`) or a percentage (e.g., `"50%"`).
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let length = match value.strip_suffix('%') {
+            Some(percent) => percent
                 .parse::<f64>()
-                .expect("invalid height")
-                / 100.0;
-            (screen_height as f64 * height_pct) as u32
-        } else {
-            self.height.parse().expect("invalid height")
+                .ok()
+                .filter(|percent| percent.is_finite() && *percent > 0.0)
+                .map(Length::Percent),
+            None => value
+                .parse::<u32>()
+                .ok()
+                .filter(|pixels| *pixels > 0)
+                .map(Length::Pixels),
         };
-        (width, height)
+        length.ok_or_else(|| {
+            format!(
+                "expected pixels (e.g., \"800\") or a percentage (e.g., \"50%\"), got {value:?}"
+            )
+        })
+    }
+}
+
+/// The size of an instance's window.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Geometry {
+    pub(crate) width: Length,
+    pub(crate) height: Length,
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Self {
+            width: Length::Percent(66.0),
+            height: Length::Percent(50.0),
+        }
+    }
+}
+
+impl Geometry {
+    /// Get the dimensions of the instance based on the screen dimensions, kept within the screen.
+    pub(crate) fn get_dimensions(&self, screen_width: u16, screen_height: u16) -> (u32, u32) {
+        (
+            self.width.to_pixels(screen_width),
+            self.height.to_pixels(screen_height),
+        )
     }
 }
 
 pub(crate) struct Context {
     pub matcher: WindowMatcher,
+    /// The instance's main window, which is positioned and decorated.
     pub window_id: Option<u32>,
+    /// Other windows belonging to the instance, such as its dialogs, shown and hidden with it.
+    pub windows: BTreeSet<u32>,
+    /// Windows qurop has hidden, whose unmapping shouldn't be mistaken for the window going away.
+    pub expected_unmaps: BTreeSet<u32>,
+    /// The PID of the most recently spawned program.
+    pub pid: Option<u32>,
+    /// How the most recently spawned program exited, if it has.
+    pub exit_status: Option<ExitStatus>,
+    /// When the server started managing the instance.
+    pub started: Instant,
+    /// Whether the window was taken over, rather than belonging to a program qurop launched.
+    pub adopted: bool,
 }
 
-#[derive(Debug, Clone)]
+impl Context {
+    /// Set the main window, forgetting the windows that belonged with the previous one.
+    pub(crate) fn set_window(&mut self, window_id: Option<u32>) {
+        self.window_id = window_id;
+        self.windows.clear();
+        self.expected_unmaps.clear();
+    }
+
+    /// Get the main window along with every other window belonging to the instance.
+    pub(crate) fn owned_windows(&self) -> BTreeSet<u32> {
+        self.window_id
+            .iter()
+            .chain(&self.windows)
+            .copied()
+            .collect()
+    }
+
+    /// Determine if the window belongs to the instance.
+    pub(crate) fn owns(&self, window_id: u32) -> bool {
+        self.window_id == Some(window_id) || self.windows.contains(&window_id)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) enum WindowMatcher {
     ProcessId(Option<u32>),
     WmClass(String),
+    WmInstance(String),
+    Role(String),
+    Title(TitleMatcher),
+    /// A sandboxed (e.g., flatpak) app, whose windows report PIDs from inside its PID namespace.
+    /// Matched by app ID if known, otherwise by being started by the given process.
+    Sandbox {
+        pid: Option<u32>,
+        app_id: Option<String>,
+    },
+    /// Every matcher must match.
+    All(Vec<WindowMatcher>),
+    /// At least one matcher must match.
+    Any(Vec<WindowMatcher>),
+    /// The matcher must not match.
+    Not(Box<WindowMatcher>),
+    /// A specific window, such as one captured from the desktop.
+    Window(u32),
+}
+
+impl WindowMatcher {
+    /// Point every process matcher at the given PID, returning whether there were any.
+    pub(crate) fn set_pid(&mut self, pid: u32) -> bool {
+        match self {
+            WindowMatcher::ProcessId(process_id)
+            | WindowMatcher::Sandbox {
+                pid: process_id, ..
+            } => {
+                *process_id = Some(pid);
+                true
+            }
+            WindowMatcher::All(matchers) | WindowMatcher::Any(matchers) => {
+                let mut found = false;
+                for matcher in matchers {
+                    found |= matcher.set_pid(pid);
+                }
+                found
+            }
+            WindowMatcher::Not(matcher) => matcher.set_pid(pid),
+            WindowMatcher::WmClass(_)
+            | WindowMatcher::WmInstance(_)
+            | WindowMatcher::Role(_)
+            | WindowMatcher::Title(_)
+            | WindowMatcher::Window(_) => false,
+        }
+    }
+}
+
+/// How a window's title is compared against the configured one.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum TitleMatcher {
+    Exact(String),
+    Substring(String),
+    Regex(#[serde(with = "regex_pattern")] Regex),
+}
+
+impl TitleMatcher {
+    pub(crate) fn matches(&self, title: &str) -> bool {
+        match self {
+            TitleMatcher::Exact(expected) => title == expected,
+            TitleMatcher::Substring(expected) => title.contains(expected.as_str()),
+            TitleMatcher::Regex(pattern) => pattern.is_match(title),
+        }
+    }
+}
+
+/// (De)serialize a regex as its pattern.
+mod regex_pattern {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(
+        regex: &Regex,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(regex.as_str())
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Regex, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for WindowMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowMatcher::ProcessId(Some(pid)) => write!(f, "pid {pid}"),
+            WindowMatcher::ProcessId(None) => write!(f, "pid (pending)"),
+            WindowMatcher::WmClass(class_name) => write!(f, "class {class_name}"),
+            WindowMatcher::WmInstance(instance_name) => write!(f, "instance {instance_name}"),
+            WindowMatcher::Role(role) => write!(f, "role {role}"),
+            WindowMatcher::Sandbox {
+                app_id: Some(app_id),
+                ..
+            } => write!(f, "sandbox {app_id}"),
+            WindowMatcher::Sandbox { pid: Some(pid), .. } => write!(f, "sandbox started by {pid}"),
+            WindowMatcher::Sandbox { .. } => write!(f, "sandbox (pending)"),
+            WindowMatcher::Title(TitleMatcher::Exact(title)) => write!(f, "title {title:?}"),
+            WindowMatcher::Title(TitleMatcher::Substring(title)) => {
+                write!(f, "title containing {title:?}")
+            }
+            WindowMatcher::Title(TitleMatcher::Regex(pattern)) => {
+                write!(f, "title matching /{pattern}/")
+            }
+            WindowMatcher::All(matchers) => write!(f, "all({})", join(matchers)),
+            WindowMatcher::Any(matchers) => write!(f, "any({})", join(matchers)),
+            WindowMatcher::Not(matcher) => write!(f, "not({matcher})"),
+            WindowMatcher::Window(window_id) => write!(f, "window {window_id:#x}"),
+        }
+    }
+}
+
+/// Join matchers into a comma-separated list.
+fn join(matchers: &[WindowMatcher]) -> String {
+    matchers
+        .iter()
+        .map(|matcher| matcher.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_parse_with_or_without_prefix() {
+        assert_eq!("SIGTERM".parse(), Ok(Signal::TERM));
+        assert_eq!("term".parse(), Ok(Signal::TERM));
+        assert_eq!("SigKill".parse(), Ok(Signal::KILL));
+        assert_eq!(
+            "hup".parse::<Signal>().map(Signal::number),
+            Ok(libc::SIGHUP)
+        );
+    }
+
+    #[test]
+    fn unknown_signals_are_rejected() {
+        for name in ["SIG", "TERMINATE", "SIGSTOP", "15", ""] {
+            let err = name.parse::<Signal>().unwrap_err();
+            assert!(err.starts_with("expected one of SIGHUP"), "{name}: {err}");
+        }
+    }
+
+    #[test]
+    fn signals_display_their_full_name() {
+        assert_eq!(Signal::TERM.to_string(), "SIGTERM");
+        assert_eq!(
+            Signal(libc::SIGSTOP).to_string(),
+            format!("signal {}", libc::SIGSTOP)
+        );
+    }
+
+    #[test]
+    fn lengths_are_positive_pixels_or_percentages() {
+        assert!(matches!("800".parse(), Ok(Length::Pixels(800))));
+        assert!(matches!("50%".parse(), Ok(Length::Percent(p)) if p == 50.0));
+        for value in ["0", "0%", "-5%", "NaN%", "inf%", "wide", "80 %", ""] {
+            assert!(value.parse::<Length>().is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn dimensions_stay_within_the_screen() {
+        let geometry = Geometry {
+            width: Length::Pixels(4000),
+            height: Length::Percent(150.0),
+        };
+        assert_eq!(geometry.get_dimensions(1920, 1080), (1920, 1080));
+        assert_eq!(Geometry::default().get_dimensions(1000, 800), (660, 400));
+        assert_eq!(Geometry::default().get_dimensions(1, 1), (1, 1));
+    }
+
+    #[test]
+    fn invalid_geometry_names_the_dimension() {
+        let geometry = WindowGeometry {
+            width: "50%".into(),
+            height: "tall".into(),
+        };
+        assert!(geometry.parse().unwrap_err().starts_with("invalid height:"));
+    }
 }
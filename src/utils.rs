@@ -1,5 +1,5 @@
 use colored::*;
-use std::process;
+use std::{process, time::Duration};
 
 pub(crate) fn abort(message: &str) -> ! {
     print_error(message);
@@ -9,3 +9,30 @@ pub(crate) fn abort(message: &str) -> ! {
 pub(crate) fn print_error(message: &str) {
     eprintln!("{}", message.red());
 }
+
+/// Format a duration as a compact, human-readable string (e.g., "1h 2m 3s").
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}h {minutes}m {seconds}s")
+    } else if minutes > 0 {
+        format!("{minutes}m {seconds}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_omit_leading_zero_units() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(format_duration(Duration::from_millis(59_999)), "59s");
+        assert_eq!(format_duration(Duration::from_secs(123)), "2m 3s");
+        assert_eq!(format_duration(Duration::from_secs(3600)), "1h 0m 0s");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1h 2m 3s");
+    }
+}
@@ -1,18 +1,22 @@
 use std::{
-    sync::{mpsc, Arc, RwLock},
+    collections::BTreeSet,
+    sync::{Arc, Condvar, Mutex},
     thread,
-    time::{Duration, SystemTime},
+    time::Duration,
 };
 use tracing::{debug, info, trace, warn};
 use x11rb::{
     connection::Connection,
+    errors::{ConnectionError, ReplyError},
     properties::WmClass,
-    protocol::{xproto::*, Event},
+    protocol::{xproto::*, ErrorKind, Event},
+    rust_connection::RustConnection,
     wrapper::ConnectionExt as WrapperConnectionExt,
 };
 
 use crate::{
     errors::Error,
+    procfs,
     structs::{Instance, WindowMatcher},
 };
 
@@ -21,336 +25,1048 @@ x11rb::atom_manager! {
     AtomsCookie {
         _MOTIF_WM_HINTS,
         _NET_ACTIVE_WINDOW,
+        _NET_CLIENT_LIST,
         _NET_WM_CLASS,
         _NET_WM_NAME,
         _NET_WM_PID,
         _NET_WM_WINDOW_TYPE,
+        _NET_WM_WINDOW_TYPE_NORMAL,
+        WM_WINDOW_ROLE,
         _KDE_NET_WM_WINDOW_TYPE_OVERRIDE,
+        _QUROP_DAEMON_PID,
+        _QUROP_INSTANCE,
+        _QUROP_ORIG_MOTIF_WM_HINTS,
+        _QUROP_ORIG_NET_WM_WINDOW_TYPE,
+        _QUROP_RELATED,
         UTF8_STRING,
     }
 }
 
-trait QuropConnectionExt {
-    fn flush_and_sync(&self);
+/// Something that happened to a window, as reported by [`Display::handle_window`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum WindowEvent {
+    /// The window became the active window.
+    Activated(u32),
+    /// A watched window was unmapped.
+    Unmapped(u32),
+    /// A watched window was destroyed.
+    Destroyed(u32),
 }
 
-impl QuropConnectionExt for x11rb::rust_connection::RustConnection {
-    fn flush_and_sync(&self) {
-        self.flush().expect("couldn't flush to x11");
-        self.sync().expect("couldn't sync with x11");
+/// How many `WM_TRANSIENT_FOR` links to follow from a window before giving up.
+const MAX_TRANSIENT_DEPTH: usize = 16;
+
+/// Build an error for a failed request concerning the given window, noting if the window is gone.
+fn window_error(window_id: u32) -> impl Fn(ReplyError) -> Error {
+    move |err| match err {
+        ReplyError::X11Error(ref x11_error)
+            if matches!(
+                x11_error.error_kind,
+                ErrorKind::Window | ErrorKind::Drawable
+            ) =>
+        {
+            Error::WindowGone(window_id)
+        }
+        err => err.into(),
     }
 }
 
-/// Find the window that is currently active on the given screen
-pub(crate) fn get_active_window(
-    connection: &x11rb::rust_connection::RustConnection,
-    screen: &Screen,
-    atom: u32,
-) -> Result<u32, ()> {
-    let response = connection
-        .get_property(false, screen.root, atom, AtomEnum::WINDOW, 0, 1)
-        .unwrap()
-        .reply()
-        .unwrap();
+/// Determine if the error means the connection to the X server has been lost.
+pub(crate) fn is_disconnect(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::XConnection {
+            source: ConnectionError::IoError(_) | ConnectionError::UnknownError,
+        } | Error::XReply {
+            source: ReplyError::ConnectionError(
+                ConnectionError::IoError(_) | ConnectionError::UnknownError
+            ),
+        }
+    )
+}
+
+/// Determine if a window PID, as reported from inside a sandbox's PID namespace, belongs to the
+/// sandboxed app. The same namespace PID is shared by every sandbox, so the host process is
+/// identified by its app ID, or failing that, by having been started by `pid`.
+fn sandbox_matches(
+    window_pid: u32,
+    pid: Option<u32>,
+    app_id: &Option<String>,
+    sandboxes: &procfs::SandboxedPids,
+) -> bool {
+    sandboxes
+        .host_pids(window_pid)
+        .iter()
+        .any(|host_pid| match app_id {
+            Some(app_id) => procfs::flatpak_app_id(*host_pid).as_ref() == Some(app_id),
+            None => pid.is_some_and(|pid| procfs::is_descendant(*host_pid, pid)),
+        })
+}
+
+/// A connection to the X server, along with the screen and atoms every request needs.
+pub(crate) struct Display {
+    connection: RustConnection,
+    screen_num: usize,
+    atoms: Atoms,
+}
+
+/// Tracks changes to the window tree, so threads waiting on a window can sleep until one appears.
+#[derive(Default)]
+pub(crate) struct WindowChanges {
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl WindowChanges {
+    /// Get the current generation, to be passed to `wait` once the windows have been checked.
+    pub(crate) fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
 
-    if response.value32().is_none() {
-        return Err(());
+    /// Wake every thread waiting on a change.
+    fn notify(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.changed.notify_all();
     }
 
-    Ok(response.to_owned().value32().unwrap().next().unwrap())
+    /// Wait until the windows have changed since the `seen` generation, or the timeout elapses.
+    /// Returns the latest generation.
+    pub(crate) fn wait(&self, seen: u64, timeout: Duration) -> u64 {
+        let generation = self.generation.lock().unwrap();
+        let (generation, _) = self
+            .changed
+            .wait_timeout_while(generation, timeout, |generation| *generation == seen)
+            .unwrap();
+        *generation
+    }
 }
 
-fn query_windows(connection: &x11rb::rust_connection::RustConnection, root: u32) -> Vec<u32> {
-    let tree = connection
-        .query_tree(root)
-        .expect("unable to query tree")
-        .reply()
-        .unwrap();
-    let mut windows = vec![];
-    for child in tree.children {
-        windows.push(child);
-        windows.extend(query_windows(connection, child));
-    }
-    windows
+/// A connection to the X server shared by the daemon's threads. The connection is established on
+/// first use, and re-established if it's lost.
+#[derive(Clone, Default)]
+pub(crate) struct DisplayHandle {
+    display: Arc<Mutex<Option<Arc<Display>>>>,
+    changes: Arc<WindowChanges>,
 }
 
-/// Get the ID of the managed window
-pub(crate) fn get_qurop_window_id(
-    connection: &x11rb::rust_connection::RustConnection,
-    root: u32,
-    matcher: &WindowMatcher,
-) -> Option<u32> {
-    connection.flush_and_sync();
-    let atoms = Atoms::new(connection).unwrap().reply().unwrap();
-    let windows = query_windows(connection, root);
-    windows.into_iter().find(|child| match matcher {
-        WindowMatcher::WmClass(qurop_class) => {
-            if let Some(class_name) = get_window_class(connection, *child) {
-                // trace!("Found class {class_name} ({child})");
-                class_name == *qurop_class
-            } else {
-                false
+impl DisplayHandle {
+    /// Changes to the window tree, as seen by the window watcher.
+    pub(crate) fn changes(&self) -> &WindowChanges {
+        &self.changes
+    }
+
+    /// Get the current connection, connecting if there isn't one.
+    pub(crate) fn get(&self) -> Result<Arc<Display>, Error> {
+        let mut display = self.display.lock().unwrap();
+        match &*display {
+            Some(display) => Ok(Arc::clone(display)),
+            None => {
+                let connected = Arc::new(Display::connect()?);
+                *display = Some(Arc::clone(&connected));
+                Ok(connected)
             }
         }
-        WindowMatcher::ProcessId(process_id) => match get_window_pid(connection, *child, atoms) {
-            Some(window_process_id) => {
-                // trace!("Found pid {window_process_id} ({child})");
-                *process_id == Some(window_process_id)
+    }
+
+    /// Discard the connection, if it's still the current one, so the next use reconnects.
+    pub(crate) fn reset(&self, stale: &Arc<Display>) {
+        let mut display = self.display.lock().unwrap();
+        if display
+            .as_ref()
+            .is_some_and(|display| Arc::ptr_eq(display, stale))
+        {
+            *display = None;
+        }
+    }
+
+    /// Run the operation against the X server, reconnecting and retrying once if the connection
+    /// has been lost.
+    pub(crate) fn with<T>(&self, op: impl Fn(&Display) -> Result<T, Error>) -> Result<T, Error> {
+        let display = self.get()?;
+        match op(&display) {
+            Err(err) if is_disconnect(&err) => {
+                warn!(
+                    "Lost the connection to the X server ({}). Reconnecting.",
+                    err
+                );
+                self.reset(&display);
+                op(&*self.get()?)
             }
-            None => false,
-        },
-    })
+            result => result,
+        }
+    }
 }
 
-/// Get the name of the specified window.
-pub(crate) fn get_window_name(
-    conn: &x11rb::rust_connection::RustConnection,
-    window_id: u32,
-    atoms: Atoms,
-) -> String {
-    let name = conn
-        .get_property(
-            false,
-            window_id,
-            atoms._NET_WM_NAME,
-            atoms.UTF8_STRING,
-            0,
-            0x1000,
-        )
-        .unwrap();
-    String::from_utf8(name.reply().unwrap().value).unwrap()
-}
+impl Display {
+    /// Connect to the X server and intern the atoms.
+    fn connect() -> Result<Self, Error> {
+        let (connection, screen_num) = x11rb::connect(None)?;
+        let atoms = Atoms::new(&connection)?.reply()?;
+        info!("Connected to the X server");
+        Ok(Self {
+            connection,
+            screen_num,
+            atoms,
+        })
+    }
 
-/// Get the class of the specified window.
-pub(crate) fn get_window_class(
-    conn: &x11rb::rust_connection::RustConnection,
-    window_id: u32,
-) -> Option<String> {
-    // trace!("Getting window class for {window_id}");
-    let class = WmClass::get(conn, window_id).expect("wmclass error");
-    match class.reply() {
-        Ok(wm_class) => Some(String::from(std::str::from_utf8(wm_class.class()).unwrap())),
-        Err(_) => None,
+    fn screen(&self) -> &Screen {
+        &self.connection.setup().roots[self.screen_num]
     }
-}
 
-/// Get the class of the specified window.
-pub(crate) fn get_window_pid(
-    conn: &x11rb::rust_connection::RustConnection,
-    window_id: u32,
-    atoms: Atoms,
-) -> Option<u32> {
-    // trace!("Getting window pid for {window_id}");
-    let property = conn.get_property(
-        false,
-        window_id,
-        atoms._NET_WM_PID,
-        AtomEnum::CARDINAL,
-        0,
-        1024,
-    );
-    match property.expect("couldn't get property").reply() {
-        Ok(reply) => {
-            if let Some(mut vals) = reply.value32() {
-                let val = &vals.next().expect("no values");
-                Some(*val)
-            } else {
-                // trace!("couldn't find pid for {window_id}");
-                None
+    fn flush_and_sync(&self) -> Result<(), Error> {
+        self.connection.flush()?;
+        self.connection.sync()?;
+        Ok(())
+    }
+
+    /// Find the window that is currently active on the screen
+    pub(crate) fn get_active_window(&self) -> Result<Option<u32>, Error> {
+        let response = self
+            .connection
+            .get_property(
+                false,
+                self.screen().root,
+                self.atoms._NET_ACTIVE_WINDOW,
+                AtomEnum::WINDOW,
+                0,
+                1,
+            )?
+            .reply()?;
+        Ok(response.value32().and_then(|mut values| values.next()))
+    }
+
+    fn query_windows(&self, root: u32) -> Result<Vec<u32>, Error> {
+        let tree = match self.connection.query_tree(root)?.reply() {
+            Ok(tree) => tree,
+            // The window was destroyed while we were walking the tree.
+            Err(err) => match window_error(root)(err) {
+                Error::WindowGone(_) => return Ok(vec![]),
+                err => return Err(err),
+            },
+        };
+        let mut windows = vec![];
+        for child in tree.children {
+            windows.push(child);
+            windows.extend(self.query_windows(child)?);
+        }
+        Ok(windows)
+    }
+
+    /// Get the windows managed by the window manager, if it publishes `_NET_CLIENT_LIST`.
+    fn get_client_list(&self) -> Result<Option<Vec<u32>>, Error> {
+        let reply = self
+            .connection
+            .get_property(
+                false,
+                self.screen().root,
+                self.atoms._NET_CLIENT_LIST,
+                AtomEnum::WINDOW,
+                0,
+                u32::MAX,
+            )?
+            .reply()?;
+        Ok(reply.value32().map(|windows| windows.collect()))
+    }
+
+    /// Get the windows to match against, preferring the window manager's client list to walking
+    /// the tree. Windows qurop has hidden aren't in the client list.
+    fn candidate_windows(&self) -> Result<Vec<u32>, Error> {
+        if let Some(windows) = self.get_client_list()? {
+            return Ok(windows);
+        }
+        debug!("No client list published, walking the window tree");
+        self.query_windows(self.screen().root)
+    }
+
+    /// Determine if the window could be an application's main window, rather than an input-only,
+    /// override-redirect (e.g., a menu or tooltip), or transient (e.g., a dialog) window.
+    fn is_toplevel(&self, window_id: u32) -> Result<bool, Error> {
+        let attributes = self
+            .connection
+            .get_window_attributes(window_id)?
+            .reply()
+            .map_err(window_error(window_id))?;
+        if attributes.class == WindowClass::INPUT_ONLY || attributes.override_redirect {
+            return Ok(false);
+        }
+        Ok(self.get_transient_for(window_id)?.is_none())
+    }
+
+    /// Get the window that the window is a dialog (or other transient) for, if any.
+    fn get_transient_for(&self, window_id: u32) -> Result<Option<u32>, Error> {
+        let transient_for = self
+            .connection
+            .get_property(
+                false,
+                window_id,
+                AtomEnum::WM_TRANSIENT_FOR,
+                AtomEnum::WINDOW,
+                0,
+                1,
+            )?
+            .reply()
+            .map_err(window_error(window_id))?;
+        Ok(transient_for
+            .value32()
+            .and_then(|mut values| values.next())
+            .filter(|parent| *parent != x11rb::NONE))
+    }
+
+    /// Determine if the window belongs to the instance owning `owned`: it's tagged for the
+    /// instance, accepted by its matcher, or a transient of one of those windows.
+    pub(crate) fn belongs_to(
+        &self,
+        window_id: u32,
+        instance_name: &str,
+        matcher: &WindowMatcher,
+        owned: &BTreeSet<u32>,
+    ) -> Result<bool, Error> {
+        let sandboxes = procfs::SandboxedPids::default();
+        self.is_related(window_id, instance_name, matcher, owned, &sandboxes)
+    }
+
+    /// Determine if the window belongs to the instance owning `owned`, as part of a search sharing
+    /// `sandboxes`.
+    fn is_related(
+        &self,
+        window_id: u32,
+        instance_name: &str,
+        matcher: &WindowMatcher,
+        owned: &BTreeSet<u32>,
+        sandboxes: &procfs::SandboxedPids,
+    ) -> Result<bool, Error> {
+        let mut current = window_id;
+        // Transients can be chained (e.g., a file picker opened from a preferences dialog), but
+        // a broken client could set up a cycle.
+        for _ in 0..MAX_TRANSIENT_DEPTH {
+            if owned.contains(&current) {
+                return Ok(true);
+            }
+            if let Some(tag) = self.get_window_tag(current)? {
+                return Ok(tag == instance_name);
+            }
+            if self.evaluate(current, matcher, sandboxes)? {
+                return Ok(true);
+            }
+            match self.get_transient_for(current)? {
+                Some(parent) => current = parent,
+                None => return Ok(false),
             }
         }
-        Err(_) => None,
+        Ok(false)
     }
-}
 
-/// Handle window focus changes.
-pub(crate) fn handle_window(tx: mpsc::Sender<String>, ctx: &Arc<RwLock<crate::Context>>) {
-    let (connection, num) = x11rb::connect(None).expect("x11 connection missing");
-    let screen = &connection.setup().roots[num];
-    let root = screen.root;
-    let atoms = Atoms::new(&connection).unwrap().reply().unwrap();
-    let active_atom = atoms._NET_ACTIVE_WINDOW;
-    connection.flush_and_sync();
-    let mut count = 0;
-    let start = SystemTime::now();
-    let window_id = loop {
-        let ctx_win = { ctx.read().unwrap().window_id };
-        match ctx_win {
-            Some(win_id) => break win_id,
-            None => {
-                count += 1;
-                if count % 5 == 0 {
-                    warn!("window not provided in {} attempts", count);
-                    thread::sleep(Duration::from_millis(100));
-                    connection.flush_and_sync();
-                } else if SystemTime::now().duration_since(start).unwrap() > Duration::from_secs(5)
-                {
-                    panic!("could not find window in 5 seconds and {} attempts", count);
+    /// Find the windows the window manager is showing that belong to the instance owning `owned`,
+    /// other than those already known.
+    pub(crate) fn find_related_windows(
+        &self,
+        instance_name: &str,
+        matcher: &WindowMatcher,
+        owned: &BTreeSet<u32>,
+    ) -> Result<BTreeSet<u32>, Error> {
+        let mut related = BTreeSet::new();
+        let sandboxes = procfs::SandboxedPids::default();
+        for window_id in self.candidate_windows()? {
+            if owned.contains(&window_id) {
+                continue;
+            }
+            match self.is_related(window_id, instance_name, matcher, owned, &sandboxes) {
+                Ok(true) => {
+                    self.watch_window(window_id)?;
+                    related.insert(window_id);
                 }
+                Ok(false) | Err(Error::WindowGone(_)) => {}
+                Err(err) => return Err(err),
             }
-        };
-    };
-    let active_class = get_window_class(&connection, window_id).unwrap_or_else(|| "unknown".into());
-    let active_name = get_window_name(&connection, window_id, atoms);
-    debug!("Active window: {window_id} ({active_name} | {active_class})");
-    let event_sub = ChangeWindowAttributesAux::default().event_mask(EventMask::PROPERTY_CHANGE);
-    connection
-        .change_window_attributes(root, &event_sub)
-        .expect("couldn't watch attributes");
-    info!("starting waiting for events with window {}", window_id);
-    connection.flush().unwrap();
-    loop {
-        let event = connection
-            .wait_for_event()
-            .expect("could not wait for xserver events");
-
-        if let Event::PropertyNotify(e) = event {
-            trace!("Property notify event for {}", e.atom);
-            if e.atom == active_atom {
-                if let Ok(active_window) = get_active_window(&connection, screen, active_atom) {
-                    if active_window != window_id {
-                        debug!("sending hide request: {} != {}", active_window, window_id);
-                        tx.send(format!("hide:{window_id}"))
-                            .expect("couldn't send hide command");
+        }
+        Ok(related)
+    }
+
+    /// Determine if the window matches the matcher. Windows that disappear mid-check don't match.
+    fn window_matches(
+        &self,
+        window_id: u32,
+        matcher: &WindowMatcher,
+        sandboxes: &procfs::SandboxedPids,
+    ) -> Result<bool, Error> {
+        let result = self.evaluate(window_id, matcher, sandboxes);
+        match result.and_then(|matches| Ok(matches && self.is_toplevel(window_id)?)) {
+            Err(Error::WindowGone(_)) => Ok(false),
+            result => result,
+        }
+    }
+
+    /// Evaluate the matcher, and any matchers nested within it, against the window.
+    fn evaluate(
+        &self,
+        window_id: u32,
+        matcher: &WindowMatcher,
+        sandboxes: &procfs::SandboxedPids,
+    ) -> Result<bool, Error> {
+        match matcher {
+            WindowMatcher::WmClass(qurop_class) => self
+                .get_window_class(window_id)
+                .map(|names| names.is_some_and(|(_, class_name)| class_name == *qurop_class)),
+            WindowMatcher::WmInstance(qurop_instance) => {
+                self.get_window_class(window_id).map(|names| {
+                    names.is_some_and(|(instance_name, _)| instance_name == *qurop_instance)
+                })
+            }
+            WindowMatcher::Role(qurop_role) => self
+                .get_window_role(window_id)
+                .map(|role| role.as_ref() == Some(qurop_role)),
+            // Launchers often fork or exec through wrappers, so the window may belong to any
+            // process started by the program.
+            WindowMatcher::ProcessId(process_id) => {
+                self.get_window_pid(window_id).map(|window_process_id| {
+                    window_process_id
+                        .zip(*process_id)
+                        .is_some_and(|(window_pid, pid)| procfs::is_descendant(window_pid, pid))
+                })
+            }
+            WindowMatcher::Title(title) => self
+                .get_window_name(window_id)
+                .map(|name| title.matches(&name)),
+            WindowMatcher::Sandbox { pid, app_id } => {
+                self.get_window_pid(window_id).map(|window_pid| {
+                    window_pid.is_some_and(|window_pid| {
+                        sandbox_matches(window_pid, *pid, app_id, sandboxes)
+                    })
+                })
+            }
+            WindowMatcher::Window(id) => Ok(window_id == *id),
+            WindowMatcher::All(matchers) => {
+                for matcher in matchers {
+                    if !self.evaluate(window_id, matcher, sandboxes)? {
+                        return Ok(false);
                     }
                 }
+                Ok(true)
             }
+            WindowMatcher::Any(matchers) => {
+                for matcher in matchers {
+                    if self.evaluate(window_id, matcher, sandboxes)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            WindowMatcher::Not(matcher) => Ok(!self.evaluate(window_id, matcher, sandboxes)?),
         }
     }
-}
 
-/// Determine if the window corresponding to the matcher is currently active.
-pub(crate) fn window_is_active(window_id: u32) -> bool {
-    let (connection, num) = x11rb::connect(None).expect("x11 connection missing");
-    let screen = &connection.setup().roots[num];
-    connection.flush_and_sync();
-    let atoms = Atoms::new(&connection).unwrap().reply().unwrap();
-    if let Ok(active_window) = get_active_window(&connection, screen, atoms._NET_ACTIVE_WINDOW) {
-        trace!("Active window ID: {}", active_window);
-        return active_window == window_id;
-    }
-    false
-}
+    /// Get the ID of the instance's window: the window tagged as the instance's, even if hidden or
+    /// withdrawn (e.g., by an app closed to the tray), or failing that, the first matching window
+    /// that isn't tagged as another instance's. Only candidate windows are matched, so unmapped
+    /// helper windows (e.g., client leaders) aren't mistaken for the instance's.
+    pub(crate) fn get_qurop_window_id(
+        &self,
+        instance_name: &str,
+        matcher: &WindowMatcher,
+    ) -> Result<Option<u32>, Error> {
+        self.flush_and_sync()?;
+        if let Some(window_id) = self.find_tagged_window(instance_name)? {
+            return Ok(Some(window_id));
+        }
+        let sandboxes = procfs::SandboxedPids::default();
+        for window_id in self.candidate_windows()? {
+            match self.get_window_tag(window_id) {
+                Ok(Some(_)) | Err(Error::WindowGone(_)) => continue,
+                Ok(None) => {}
+                Err(err) => return Err(err),
+            }
+            if self.window_matches(window_id, matcher, &sandboxes)? {
+                return Ok(Some(window_id));
+            }
+        }
+        Ok(None)
+    }
 
-/// Unmap the qurop window.
-pub(crate) fn unmap_qurop_window(matcher: &WindowMatcher) {
-    trace!("unmapping qurop");
-    let (connection, num) = x11rb::connect(None).expect("x11 connection missing");
-    let screen = &connection.setup().roots[num];
-    let qurop_window_id = match get_qurop_window_id(&connection, screen.root, matcher) {
-        Some(win_id) => win_id,
-        None => {
-            debug!("No window found");
-            return;
+    /// Get the instance name held in one of the window's tag properties, if any.
+    fn get_tag(&self, window_id: u32, property: Atom) -> Result<Option<String>, Error> {
+        let tag = self
+            .connection
+            .get_property(
+                false,
+                window_id,
+                property,
+                self.atoms.UTF8_STRING,
+                0,
+                0x1000,
+            )?
+            .reply()
+            .map_err(window_error(window_id))?;
+        Ok((!tag.value.is_empty()).then(|| String::from_utf8_lossy(&tag.value).into_owned()))
+    }
+
+    /// Get the name of the instance the window has been tagged as belonging to, if any.
+    pub(crate) fn get_window_tag(&self, window_id: u32) -> Result<Option<String>, Error> {
+        self.get_tag(window_id, self.atoms._QUROP_INSTANCE)
+    }
+
+    /// Tag the window as belonging to the instance, managed by this daemon, and watch it.
+    pub(crate) fn tag_window(&self, window_id: u32, instance_name: &str) -> Result<(), Error> {
+        debug!("Tagging window {} as '{}'", window_id, instance_name);
+        self.watch_window(window_id)?;
+        self.connection
+            .change_property8(
+                PropMode::REPLACE,
+                window_id,
+                self.atoms._QUROP_INSTANCE,
+                self.atoms.UTF8_STRING,
+                instance_name.as_bytes(),
+            )?
+            .check()
+            .map_err(window_error(window_id))?;
+        self.connection
+            .change_property32(
+                PropMode::REPLACE,
+                window_id,
+                self.atoms._QUROP_DAEMON_PID,
+                AtomEnum::CARDINAL,
+                &[std::process::id()],
+            )?
+            .check()
+            .map_err(window_error(window_id))
+    }
+
+    /// Tag a window hidden along with the instance's main window (e.g., one of its dialogs), so it
+    /// can be recovered if the daemon dies before showing it again.
+    pub(crate) fn tag_related_window(
+        &self,
+        window_id: u32,
+        instance_name: &str,
+    ) -> Result<(), Error> {
+        self.connection
+            .change_property8(
+                PropMode::REPLACE,
+                window_id,
+                self.atoms._QUROP_RELATED,
+                self.atoms.UTF8_STRING,
+                instance_name.as_bytes(),
+            )?
+            .check()
+            .map_err(window_error(window_id))
+    }
+
+    /// Show a window hidden along with the instance's main window, removing its tag.
+    pub(crate) fn show_related_window(&self, window_id: u32) -> Result<(), Error> {
+        self.connection
+            .delete_property(window_id, self.atoms._QUROP_RELATED)?
+            .check()
+            .map_err(window_error(window_id))?;
+        self.map_window(window_id)
+    }
+
+    /// Watch the window's properties and structure, so we hear when it's unmapped or destroyed.
+    pub(crate) fn watch_window(&self, window_id: u32) -> Result<(), Error> {
+        let window_sub = ChangeWindowAttributesAux::default()
+            .event_mask(EventMask::PROPERTY_CHANGE | EventMask::STRUCTURE_NOTIFY);
+        // Not checked, as the window may already be gone.
+        self.connection
+            .change_window_attributes(window_id, &window_sub)?;
+        self.connection.flush()?;
+        Ok(())
+    }
+
+    /// Get the name of the specified window.
+    pub(crate) fn get_window_name(&self, window_id: u32) -> Result<String, Error> {
+        let name = self
+            .connection
+            .get_property(
+                false,
+                window_id,
+                self.atoms._NET_WM_NAME,
+                self.atoms.UTF8_STRING,
+                0,
+                0x1000,
+            )?
+            .reply()
+            .map_err(window_error(window_id))?;
+        if name.value.is_empty() {
+            // Fall back to the legacy ICCCM name for windows that don't set the EWMH one.
+            let legacy_name = self
+                .connection
+                .get_property(
+                    false,
+                    window_id,
+                    AtomEnum::WM_NAME,
+                    AtomEnum::ANY,
+                    0,
+                    0x1000,
+                )?
+                .reply()
+                .map_err(window_error(window_id))?;
+            return Ok(String::from_utf8_lossy(&legacy_name.value).into_owned());
         }
-    };
-    info!("Unmapping qurop window: {qurop_window_id}");
-    connection
-        .unmap_window(qurop_window_id)
-        .expect("could not unmap window");
-}
+        Ok(String::from_utf8_lossy(&name.value).into_owned())
+    }
 
-/// Unmap the specified window.
-pub(crate) fn unmap_window(window_id: u32) {
-    let (connection, _num) = x11rb::connect(None).expect("x11 connection missing");
-    info!("Unmapping window: {window_id}");
-    connection
-        .unmap_window(window_id)
-        .expect("could not unmap window");
-    connection.flush_and_sync();
-}
+    /// Get the instance and class names from the specified window's `WM_CLASS`.
+    pub(crate) fn get_window_class(
+        &self,
+        window_id: u32,
+    ) -> Result<Option<(String, String)>, Error> {
+        match WmClass::get(&self.connection, window_id)?.reply() {
+            Ok(wm_class) => Ok(Some((
+                String::from_utf8_lossy(wm_class.instance()).into_owned(),
+                String::from_utf8_lossy(wm_class.class()).into_owned(),
+            ))),
+            // The window has no (or a malformed) WM_CLASS.
+            Err(ReplyError::ConnectionError(ConnectionError::ParseError(_))) => Ok(None),
+            Err(err) => Err(window_error(window_id)(err)),
+        }
+    }
 
-/// Map the qurop window.
-pub(crate) fn map_qurop_window(matcher: &WindowMatcher) -> Result<u32, Error> {
-    let (connection, num) = x11rb::connect(None).expect("x11 connection missing");
-    let screen = &connection.setup().roots[num];
-    let qurop_window_id = get_qurop_window_id(&connection, screen.root, matcher)
-        .ok_or_else(|| Error::WindowNotFound)?;
-    info!("Mapping qurop window: {qurop_window_id}");
-    map_window(qurop_window_id);
-    Ok(qurop_window_id)
-}
+    /// Get the role of the specified window, if it has one.
+    pub(crate) fn get_window_role(&self, window_id: u32) -> Result<Option<String>, Error> {
+        let role = self
+            .connection
+            .get_property(
+                false,
+                window_id,
+                self.atoms.WM_WINDOW_ROLE,
+                AtomEnum::STRING,
+                0,
+                0x1000,
+            )?
+            .reply()
+            .map_err(window_error(window_id))?;
+        Ok((!role.value.is_empty()).then(|| String::from_utf8_lossy(&role.value).into_owned()))
+    }
 
-pub(crate) fn map_window(window_id: u32) {
-    let (connection, _num) = x11rb::connect(None).expect("x11 connection missing");
-    info!("Mapping window: {window_id}");
-    connection
-        .map_window(window_id)
-        .expect("could not map window");
-    connection.flush_and_sync();
-}
+    /// Get the PID of the process owning the specified window.
+    pub(crate) fn get_window_pid(&self, window_id: u32) -> Result<Option<u32>, Error> {
+        let reply = self
+            .connection
+            .get_property(
+                false,
+                window_id,
+                self.atoms._NET_WM_PID,
+                AtomEnum::CARDINAL,
+                0,
+                1024,
+            )?
+            .reply()
+            .map_err(window_error(window_id))?;
+        Ok(reply.value32().and_then(|mut values| values.next()))
+    }
 
-/// Position the window and set decoration properties.
-pub(crate) fn position_window(window_id: u32, instance: &Instance) {
-    let (connection, num) = x11rb::connect(None).expect("x11 connection missing");
-    let screen = &connection.setup().roots[num];
-    let (width, height) = instance
-        .geometry
-        .get_dimensions(screen.width_in_pixels, screen.height_in_pixels);
-    let x_pos = ((screen.width_in_pixels as u32 - width) / 2) as i32;
-    let atoms = Atoms::new(&connection).unwrap().reply().unwrap();
-    connection
-        .change_property32(
-            PropMode::REPLACE,
+    /// Watch for changes to the active window, reporting each newly-active window, along with
+    /// watched windows being unmapped or destroyed. Windows being created, mapped, or gaining the
+    /// properties matchers look at are reported to `changes`.
+    pub(crate) fn handle_window(
+        &self,
+        changes: &WindowChanges,
+        mut on_event: impl FnMut(WindowEvent),
+    ) -> Result<(), Error> {
+        let root = self.screen().root;
+        let event_sub = ChangeWindowAttributesAux::default()
+            .event_mask(EventMask::PROPERTY_CHANGE | EventMask::SUBSTRUCTURE_NOTIFY);
+        self.connection
+            .change_window_attributes(root, &event_sub)?
+            .check()?;
+        info!("starting waiting for events");
+        loop {
+            match self.connection.wait_for_event()? {
+                Event::PropertyNotify(e) if e.window == root => {
+                    trace!("Property notify event for {}", e.atom);
+                    if e.atom == self.atoms._NET_ACTIVE_WINDOW {
+                        if let Some(active_window) = self.get_active_window()? {
+                            on_event(WindowEvent::Activated(active_window));
+                        }
+                    } else if e.atom == self.atoms._NET_CLIENT_LIST {
+                        changes.notify();
+                    }
+                }
+                Event::PropertyNotify(e)
+                    if e.atom == self.atoms._NET_WM_PID
+                        || e.atom == self.atoms._NET_WM_NAME
+                        || e.atom == self.atoms.WM_WINDOW_ROLE
+                        || e.atom == u32::from(AtomEnum::WM_CLASS)
+                        || e.atom == u32::from(AtomEnum::WM_NAME) =>
+                {
+                    trace!("Window {} property {} changed", e.window, e.atom);
+                    changes.notify();
+                }
+                Event::CreateNotify(e) => {
+                    trace!("Window {} created", e.window);
+                    // New windows are watched so we hear when their PID or class is set, and when
+                    // they're mapped after being reparented by the window manager.
+                    self.watch_window(e.window)?;
+                    changes.notify();
+                }
+                // Children of the root window are also reported to the root, so only the window's
+                // own notifications are passed on.
+                Event::UnmapNotify(e) if e.event == e.window => {
+                    trace!("Window {} unmapped", e.window);
+                    on_event(WindowEvent::Unmapped(e.window));
+                }
+                Event::DestroyNotify(e) if e.event == e.window => {
+                    trace!("Window {} destroyed", e.window);
+                    on_event(WindowEvent::Destroyed(e.window));
+                }
+                Event::MapNotify(e) => {
+                    trace!("Window {} mapped", e.window);
+                    changes.notify();
+                }
+                Event::ReparentNotify(e) => {
+                    trace!("Window {} reparented", e.window);
+                    changes.notify();
+                }
+                Event::Error(err) => trace!(?err, "Ignoring X error"),
+                _ => {}
+            }
+        }
+    }
+
+    /// Determine if the window is currently mapped and viewable.
+    pub(crate) fn window_is_mapped(&self, window_id: u32) -> Result<bool, Error> {
+        let attributes = self
+            .connection
+            .get_window_attributes(window_id)?
+            .reply()
+            .map_err(window_error(window_id))?;
+        Ok(attributes.map_state == MapState::VIEWABLE)
+    }
+
+    /// Unmap the qurop window.
+    pub(crate) fn unmap_qurop_window(
+        &self,
+        instance_name: &str,
+        matcher: &WindowMatcher,
+    ) -> Result<(), Error> {
+        trace!("unmapping qurop");
+        let qurop_window_id = match self.get_qurop_window_id(instance_name, matcher)? {
+            Some(win_id) => win_id,
+            None => {
+                debug!("No window found");
+                return Ok(());
+            }
+        };
+        info!("Unmapping qurop window: {qurop_window_id}");
+        self.unmap_window(qurop_window_id)
+    }
+
+    /// Unmap the specified window.
+    pub(crate) fn unmap_window(&self, window_id: u32) -> Result<(), Error> {
+        info!("Unmapping window: {window_id}");
+        self.connection
+            .unmap_window(window_id)?
+            .check()
+            .map_err(window_error(window_id))
+    }
+
+    /// Map the qurop window, tagging it as the instance's.
+    pub(crate) fn map_qurop_window(
+        &self,
+        instance_name: &str,
+        matcher: &WindowMatcher,
+    ) -> Result<u32, Error> {
+        let qurop_window_id = self
+            .get_qurop_window_id(instance_name, matcher)?
+            .ok_or(Error::WindowNotFound)?;
+        let name = self.get_window_name(qurop_window_id)?;
+        info!("Mapping qurop window: {qurop_window_id} ({name})");
+        self.tag_window(qurop_window_id, instance_name)?;
+        self.map_window(qurop_window_id)?;
+        Ok(qurop_window_id)
+    }
+
+    pub(crate) fn map_window(&self, window_id: u32) -> Result<(), Error> {
+        info!("Mapping window: {window_id}");
+        self.connection
+            .map_window(window_id)?
+            .check()
+            .map_err(window_error(window_id))
+    }
+
+    /// Keep a copy of one of the window's properties in `saved`, unless a copy is already kept. A
+    /// property the window doesn't have is kept as an empty one, so it's removed again on restore.
+    fn save_property(&self, window_id: u32, property: Atom, saved: Atom) -> Result<(), Error> {
+        let existing = self
+            .connection
+            .get_property(false, window_id, saved, AtomEnum::ANY, 0, 0)?
+            .reply()
+            .map_err(window_error(window_id))?;
+        if existing.type_ != x11rb::NONE {
+            return Ok(());
+        }
+        let original = self
+            .connection
+            .get_property(false, window_id, property, AtomEnum::ANY, 0, 0x1000)?
+            .reply()
+            .map_err(window_error(window_id))?;
+        let (type_, format) = match original.type_ {
+            x11rb::NONE => (AtomEnum::CARDINAL.into(), 32),
+            type_ => (type_, original.format),
+        };
+        self.connection
+            .change_property(
+                PropMode::REPLACE,
+                window_id,
+                saved,
+                type_,
+                format,
+                original.value_len,
+                &original.value,
+            )?
+            .check()
+            .map_err(window_error(window_id))
+    }
+
+    /// Put back a property kept by `save_property`, returning whether a copy was kept.
+    fn restore_property(&self, window_id: u32, property: Atom, saved: Atom) -> Result<bool, Error> {
+        let original = self
+            .connection
+            .get_property(false, window_id, saved, AtomEnum::ANY, 0, 0x1000)?
+            .reply()
+            .map_err(window_error(window_id))?;
+        if original.type_ == x11rb::NONE {
+            return Ok(false);
+        }
+        if original.value.is_empty() {
+            self.connection
+                .delete_property(window_id, property)?
+                .check()
+                .map_err(window_error(window_id))?;
+        } else {
+            self.connection
+                .change_property(
+                    PropMode::REPLACE,
+                    window_id,
+                    property,
+                    original.type_,
+                    original.format,
+                    original.value_len,
+                    &original.value,
+                )?
+                .check()
+                .map_err(window_error(window_id))?;
+        }
+        self.connection
+            .delete_property(window_id, saved)?
+            .check()
+            .map_err(window_error(window_id))?;
+        Ok(true)
+    }
+
+    /// Put back the decoration properties replaced by `position_window`, remove the window's tags,
+    /// and show the window.
+    pub(crate) fn restore_window(&self, window_id: u32) -> Result<(), Error> {
+        info!("Restoring window: {window_id}");
+        let restored_type = self.restore_property(
             window_id,
-            atoms._NET_WM_WINDOW_TYPE,
-            AtomEnum::ATOM,
-            &[atoms._KDE_NET_WM_WINDOW_TYPE_OVERRIDE],
-        )
-        .expect("setting kwin property");
-    connection
-        .change_property32(
-            PropMode::REPLACE,
+            self.atoms._NET_WM_WINDOW_TYPE,
+            self.atoms._QUROP_ORIG_NET_WM_WINDOW_TYPE,
+        )?;
+        let restored_hints = self.restore_property(
             window_id,
-            atoms._MOTIF_WM_HINTS,
-            AtomEnum::CARDINAL,
-            &[2, 0, 0, 0, 0],
-        )
-        .expect("setting motif property");
-    // We position and resize in two passes due to needing a messy hack to address possible races
-    // with application startup. Certain terminal emulators, such as Wezterm, don't register resize
-    // events until a certain point in their startup. Sleeping seems to handle this well enough,
-    // but it adds some visual jank if the window launches centered and then snaps to the top of
-    // the screen after the interval. By immediately positioning it at the top and then resizing,
-    // we can minimize the jank.
-    let existing_config = connection
-        .get_geometry(window_id)
-        .expect("couldn't get window geometry")
-        .reply()
-        .expect("no reply");
-    let intermediate_width = if existing_config.width as u32 != width {
-        // Get the window close to final size, but leave room to trigger an event after the sleep
-        Some(width - 1)
-    } else {
-        // Don't resize it down if it's already correct
-        None
-    };
-    let window_position_config = ConfigureWindowAux::new()
-        .x(Some(x_pos))
-        .y(Some(0))
-        .width(intermediate_width)
-        .border_width(Some(0));
-    debug!(
-        "Positioning window {} to: {:?}",
-        window_id, window_position_config
-    );
-    connection
-        .configure_window(window_id, &window_position_config)
-        .expect("couldn't configure window");
-    connection.flush_and_sync();
-    let window_geometry_config = ConfigureWindowAux::new()
-        .height(Some(height))
-        .width(Some(width));
-    debug!(
-        "Resizing window {} to: {:?}",
-        window_id, window_geometry_config
-    );
-    connection
-        .configure_window(window_id, &window_geometry_config)
-        .expect("couldn't configure window");
-    if let Some(window_delay_ms) = instance.window_delay {
-        // Ugly hack that makes me sad.
-        thread::sleep(Duration::from_millis(window_delay_ms));
-    }
-    connection.flush_and_sync();
+            self.atoms._MOTIF_WM_HINTS,
+            self.atoms._QUROP_ORIG_MOTIF_WM_HINTS,
+        )?;
+        // qurop 0.1 didn't keep the originals, so windows it decorated get the defaults back.
+        if !restored_type && !restored_hints && self.is_qurop_decorated(window_id)? {
+            self.connection
+                .change_property32(
+                    PropMode::REPLACE,
+                    window_id,
+                    self.atoms._NET_WM_WINDOW_TYPE,
+                    AtomEnum::ATOM,
+                    &[self.atoms._NET_WM_WINDOW_TYPE_NORMAL],
+                )?
+                .check()
+                .map_err(window_error(window_id))?;
+            self.connection
+                .delete_property(window_id, self.atoms._MOTIF_WM_HINTS)?
+                .check()
+                .map_err(window_error(window_id))?;
+        }
+        for property in [
+            self.atoms._QUROP_INSTANCE,
+            self.atoms._QUROP_RELATED,
+            self.atoms._QUROP_DAEMON_PID,
+        ] {
+            self.connection
+                .delete_property(window_id, property)?
+                .check()
+                .map_err(window_error(window_id))?;
+        }
+        self.map_window(window_id)
+    }
+
+    /// Determine if the window carries the decoration properties set by `position_window`.
+    fn is_qurop_decorated(&self, window_id: u32) -> Result<bool, Error> {
+        let window_type = self
+            .connection
+            .get_property(
+                false,
+                window_id,
+                self.atoms._NET_WM_WINDOW_TYPE,
+                AtomEnum::ATOM,
+                0,
+                1,
+            )?
+            .reply()
+            .map_err(window_error(window_id))?;
+        let motif_hints = self
+            .connection
+            .get_property(
+                false,
+                window_id,
+                self.atoms._MOTIF_WM_HINTS,
+                AtomEnum::CARDINAL,
+                0,
+                5,
+            )?
+            .reply()
+            .map_err(window_error(window_id))?;
+        Ok(window_type
+            .value32()
+            .and_then(|mut types| types.next())
+            .is_some_and(|window_type| window_type == self.atoms._KDE_NET_WM_WINDOW_TYPE_OVERRIDE)
+            && motif_hints
+                .value32()
+                .is_some_and(|hints| hints.eq([2, 0, 0, 0, 0])))
+    }
+
+    /// Determine if the window is mapped, and so will report being unmapped.
+    pub(crate) fn is_mapped(&self, window_id: u32) -> Result<bool, Error> {
+        let attributes = self
+            .connection
+            .get_window_attributes(window_id)?
+            .reply()
+            .map_err(window_error(window_id))?;
+        Ok(attributes.map_state != MapState::UNMAPPED)
+    }
+
+    /// Determine if the window was hidden by qurop and never shown again.
+    fn is_orphaned(&self, window_id: u32) -> Result<bool, Error> {
+        let attributes = self
+            .connection
+            .get_window_attributes(window_id)?
+            .reply()
+            .map_err(window_error(window_id))?;
+        Ok(attributes.map_state == MapState::UNMAPPED
+            && !attributes.override_redirect
+            && (self.get_window_tag(window_id)?.is_some()
+                || self
+                    .get_tag(window_id, self.atoms._QUROP_RELATED)?
+                    .is_some()
+                || self.is_qurop_decorated(window_id)?))
+    }
+
+    /// Find the window tagged for the instance among every window, including withdrawn ones.
+    fn find_tagged_window(&self, instance_name: &str) -> Result<Option<u32>, Error> {
+        for window_id in self.query_windows(self.screen().root)? {
+            match self.get_window_tag(window_id) {
+                Ok(Some(tag)) if tag == instance_name => return Ok(Some(window_id)),
+                Ok(_) | Err(Error::WindowGone(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find windows that were hidden by qurop and never shown again.
+    pub(crate) fn find_orphaned_windows(&self) -> Result<Vec<u32>, Error> {
+        let mut orphans = vec![];
+        for window_id in self.query_windows(self.screen().root)? {
+            match self.is_orphaned(window_id) {
+                Ok(true) => orphans.push(window_id),
+                Ok(false) | Err(Error::WindowGone(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// Position the window and set decoration properties.
+    pub(crate) fn position_window(&self, window_id: u32, instance: &Instance) -> Result<(), Error> {
+        let screen = self.screen();
+        let (width, height) = instance
+            .geometry
+            .get_dimensions(screen.width_in_pixels, screen.height_in_pixels);
+        let x_pos = ((screen.width_in_pixels as u32).saturating_sub(width) / 2) as i32;
+        // Windows left decorated by qurop 0.1 have no originals worth keeping.
+        if !self.is_qurop_decorated(window_id)? {
+            self.save_property(
+                window_id,
+                self.atoms._NET_WM_WINDOW_TYPE,
+                self.atoms._QUROP_ORIG_NET_WM_WINDOW_TYPE,
+            )?;
+            self.save_property(
+                window_id,
+                self.atoms._MOTIF_WM_HINTS,
+                self.atoms._QUROP_ORIG_MOTIF_WM_HINTS,
+            )?;
+        }
+        self.connection
+            .change_property32(
+                PropMode::REPLACE,
+                window_id,
+                self.atoms._NET_WM_WINDOW_TYPE,
+                AtomEnum::ATOM,
+                &[self.atoms._KDE_NET_WM_WINDOW_TYPE_OVERRIDE],
+            )?
+            .check()
+            .map_err(window_error(window_id))?;
+        self.connection
+            .change_property32(
+                PropMode::REPLACE,
+                window_id,
+                self.atoms._MOTIF_WM_HINTS,
+                AtomEnum::CARDINAL,
+                &[2, 0, 0, 0, 0],
+            )?
+            .check()
+            .map_err(window_error(window_id))?;
+        // We position and resize in two passes due to needing a messy hack to address possible races
+        // with application startup. Certain terminal emulators, such as Wezterm, don't register resize
+        // events until a certain point in their startup. Sleeping seems to handle this well enough,
+        // but it adds some visual jank if the window launches centered and then snaps to the top of
+        // the screen after the interval. By immediately positioning it at the top and then resizing,
+        // we can minimize the jank.
+        let existing_config = self
+            .connection
+            .get_geometry(window_id)?
+            .reply()
+            .map_err(window_error(window_id))?;
+        let intermediate_width = if existing_config.width as u32 != width {
+            // Get the window close to final size, but leave room to trigger an event after the sleep
+            Some(width.saturating_sub(1).max(1))
+        } else {
+            // Don't resize it down if it's already correct
+            None
+        };
+        let window_position_config = ConfigureWindowAux::new()
+            .x(Some(x_pos))
+            .y(Some(0))
+            .width(intermediate_width)
+            .border_width(Some(0));
+        debug!(
+            "Positioning window {} to: {:?}",
+            window_id, window_position_config
+        );
+        self.connection
+            .configure_window(window_id, &window_position_config)?
+            .check()
+            .map_err(window_error(window_id))?;
+        let window_geometry_config = ConfigureWindowAux::new()
+            .height(Some(height))
+            .width(Some(width));
+        debug!(
+            "Resizing window {} to: {:?}",
+            window_id, window_geometry_config
+        );
+        // Not checked, as that would flush the request before the delay below.
+        self.connection
+            .configure_window(window_id, &window_geometry_config)?;
+        if let Some(window_delay_ms) = instance.window_delay {
+            // Ugly hack that makes me sad.
+            thread::sleep(Duration::from_millis(window_delay_ms));
+        }
+        self.flush_and_sync()
+    }
 }